@@ -0,0 +1,98 @@
+use std::{fs, io, path::Path};
+use urlencoding::encode;
+
+// One row of a directory listing
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<String>,
+}
+
+// Render an HTML listing of `dir`, leaving out entries `hidden` rejects. Links are
+// built from the directory's position under base_dir plus each entry name, so they
+// never point outside base_dir.
+pub fn render(dir: &Path, base_dir: &Path, hidden: impl Fn(&Path) -> bool) -> io::Result<String> {
+    let relative = dir
+        .strip_prefix(base_dir)
+        .map_err(|_| io::Error::new(io::ErrorKind::PermissionDenied, "directory outside base"))?;
+
+    // URL and display path of the directory itself, always with a trailing slash
+    let mut url = String::from("/");
+    let mut display = String::from("/");
+    for component in relative.iter() {
+        let component = component.to_string_lossy();
+        url.push_str(&encode(&component));
+        url.push('/');
+        display.push_str(&component);
+        display.push('/');
+    }
+
+    let mut entries: Vec<Entry> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| !hidden(&entry.path()))
+        .filter_map(|entry| {
+            // Follow symlinks for display; dangling ones are left out
+            let metadata = fs::metadata(entry.path()).ok()?;
+            Some(Entry {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                is_dir: metadata.is_dir(),
+                size: metadata.len(),
+                modified: metadata.modified().ok().map(httpdate::fmt_http_date),
+            })
+        })
+        .collect();
+
+    // Directories first, then by name
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+    let title = html_escape(&display);
+    let mut rows = String::new();
+    if !relative.as_os_str().is_empty() {
+        let parent = &url[..url[..url.len() - 1].rfind('/').unwrap_or(0) + 1];
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{}\">../</a></td><td></td><td></td></tr>\n",
+            parent
+        ));
+    }
+    for entry in &entries {
+        let slash = if entry.is_dir { "/" } else { "" };
+        let size = if entry.is_dir {
+            "-".to_string()
+        } else {
+            entry.size.to_string()
+        };
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{}{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            url,
+            encode(&entry.name),
+            slash,
+            html_escape(&entry.name),
+            slash,
+            size,
+            entry.modified.as_deref().unwrap_or("-")
+        ));
+    }
+
+    Ok(format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {title}</title></head>\n\
+         <body>\n<h1>Index of {title}</h1>\n<table>\n\
+         <tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n{rows}</table>\n</body>\n</html>\n"
+    ))
+}
+
+// Escape text for safe inclusion in HTML content and attribute values
+fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -1,134 +1,2483 @@
-use mime_guess::from_path;
+use crate::autoindex;
+use crate::log::{self, debug};
+use crate::structs::{Config, Stats, CONTENT_TYPE_BUCKETS};
+use crate::template;
+use crate::tls::Stream;
+use flate2::{write::GzEncoder, Compression};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use socket2::{SockRef, TcpKeepalive};
 use std::{
-    fs,
-    io::{Read, Write},
-    net::TcpStream,
+    cell::Cell,
+    collections::hash_map::DefaultHasher,
+    fs::{self, File, Metadata, OpenOptions},
+    hash::{Hash, Hasher},
+    io::{self, Read, Seek, SeekFrom, Write},
+    net::{IpAddr, TcpStream},
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 use urlencoding::decode;
 
+// Served at /favicon.ico when base_dir has no favicon of its own
+const DEFAULT_FAVICON: &[u8] = include_bytes!("favicon.ico");
+
+// Methods the server understands, and those that apply to a served file
+const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+const RESOURCE_METHODS: &str = "GET, HEAD, OPTIONS";
+
+// Outcome of evaluating a Range header against a file
+enum ByteRange {
+    Full,
+    Partial(u64, u64), // Inclusive start and end offsets
+    Unsatisfiable,
+}
+
+// Reasons a requested path cannot be served
+enum PathError {
+    NotFound,
+    TooLong,
+    Loop,               // Symlinks along the path resolve in a cycle
+    Directory(PathBuf), // Directory inside base_dir without an index file
+}
+
 // Sanitize requested path to prevent directory traversal
-fn sanitize_path(base_dir: &Path, requested_path: &str, index_file: &str) -> Option<PathBuf> {
+fn sanitize_path(
+    base_dir: &Path,
+    requested_path: &str,
+    config: &Config,
+) -> Result<PathBuf, PathError> {
+    let index_file = config.index_file.as_str();
     if base_dir.as_os_str().is_empty() || index_file.is_empty() {
-        return None;
+        return Err(PathError::NotFound);
     }
 
     // Decode URL-encoded path
-    let requested_path = decode(requested_path).ok()?.trim().to_string();
+    let mut requested_path = decode(requested_path)
+        .map_err(|_| PathError::NotFound)?
+        .trim()
+        .to_string();
 
-    // Default to index file if root is requested
-    let target_path = if requested_path == "/" || requested_path.is_empty() {
-        base_dir.join(index_file)
-    } else {
-        base_dir.join(requested_path.trim_start_matches('/'))
-    };
+    // Backslashes are suspicious unless explicitly treated as separators.
+    // Traversal via `..\` is still caught by the canonicalize check below.
+    if requested_path.contains('\\') {
+        if !config.normalize_backslashes {
+            debug!("Rejected path with backslash: {:?}", requested_path);
+            return Err(PathError::NotFound);
+        }
+        requested_path = requested_path.replace('\\', "/");
+    }
+
+    // Collapse consecutive slashes (`/a//b` -> `/a/b`) like browsers and proxies do
+    if config.merge_slashes {
+        requested_path = merge_slashes(&requested_path);
+    }
+
+    let target_path = base_dir.join(requested_path.trim_start_matches('/'));
+
+    // FOLLOW_SYMLINKS=false refuses symlinks anywhere along the path, even in-tree ones
+    if !config.follow_symlinks && has_symlink(&target_path, base_dir) {
+        debug!("Rejected symlinked path: {:?}", requested_path);
+        return Err(PathError::NotFound);
+    }
 
     // Resolve canonical path and ensure it stays within base directory
-    match target_path.canonicalize() {
+    let resolved = match target_path.canonicalize() {
         Ok(clean_path) if clean_path.starts_with(base_dir) && clean_path.is_file() => {
-            Some(clean_path)
+            Ok(clean_path)
+        }
+        // Directories serve their index file, which must itself stay within base_dir
+        Ok(clean_path) if clean_path.starts_with(base_dir) && clean_path.is_dir() => {
+            match clean_path.join(index_file).canonicalize() {
+                Ok(index)
+                    if index.starts_with(base_dir)
+                        && index.is_file()
+                        && (config.follow_symlinks
+                            || !is_symlink(&clean_path.join(index_file))) =>
+                {
+                    Ok(index)
+                }
+                _ => Err(PathError::Directory(clean_path)),
+            }
+        }
+        // Paths beyond PATH_MAX/NAME_MAX are the client's fault, not a missing file
+        Err(e) if e.raw_os_error() == Some(libc::ENAMETOOLONG) => {
+            debug!("Requested path too long ({} bytes)", requested_path.len());
+            Err(PathError::TooLong)
+        }
+        // A broken directory setup rather than a missing file, worth flagging to the operator
+        Err(e) if e.raw_os_error() == Some(libc::ELOOP) => {
+            eprintln!("Warning: symlink loop at {:?}", requested_path);
+            Err(PathError::Loop)
         }
         _ => {
-            eprintln!("Invalid path requested: {}", requested_path);
-            None
+            debug!("Invalid path requested: {:?}", requested_path);
+            Err(PathError::NotFound)
+        }
+    };
+
+    // Deny-list both the path as requested and where it resolved to, so a symlink
+    // cannot lead into or out of a denied name. Denied paths look missing.
+    match resolved {
+        Ok(ref path) | Err(PathError::Directory(ref path))
+            if is_denied(config, &target_path, base_dir) || is_denied(config, path, base_dir) =>
+        {
+            debug!("Denied path requested: {:?}", requested_path);
+            Err(PathError::NotFound)
         }
+        resolved => resolved,
     }
 }
 
-// Send an HTTP response
-fn send_response(stream: &mut TcpStream, status: &str, content: Option<&[u8]>, content_type: &str) {
-    let content_length = content.map_or(0, |c| c.len());
-
-    // Build response headers
-    let response_headers = format!(
-        "HTTP/1.1 {}\r\n\
-        Content-Type: {}\r\n\
-        Content-Length: {}\r\n\
-        Connection: close\r\n\
-        \r\n",
-        status, content_type, content_length
+// Whether `path` itself is a symlink, without following it
+fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_symlink())
+}
+
+// Whether any component of `path` below base_dir is a symlink. Stops at the first
+// component that does not exist, which canonicalize reports as missing anyway.
+fn has_symlink(path: &Path, base_dir: &Path) -> bool {
+    let Ok(relative) = path.strip_prefix(base_dir) else {
+        return false;
+    };
+    let mut current = base_dir.to_path_buf();
+    for component in relative.components() {
+        current.push(component);
+        match fs::symlink_metadata(&current) {
+            Ok(metadata) if metadata.file_type().is_symlink() => return true,
+            Ok(_) => {}
+            Err(_) => return false,
+        }
+    }
+    false
+}
+
+// Whether a path under base_dir is hidden: any dotfile component (except
+// .well-known) unless ALLOW_DOTFILES, or a match for one of the DENY patterns
+fn is_denied(config: &Config, path: &Path, base_dir: &Path) -> bool {
+    let Ok(relative) = path.strip_prefix(base_dir) else {
+        return false;
+    };
+    let relative = relative.to_string_lossy();
+    let components = || {
+        relative
+            .split('/')
+            .filter(|component| !component.is_empty())
+    };
+
+    let dotfile = !config.allow_dotfiles
+        && components().any(|component| {
+            !matches!(component, "." | ".." | ".well-known") && component.starts_with('.')
+        });
+
+    // Patterns with a '/' match the whole relative path, others any single component
+    dotfile
+        || config.deny_patterns.iter().any(|pattern| {
+            if pattern.contains('/') {
+                glob_match(pattern.trim_start_matches('/'), &relative)
+            } else {
+                components().any(|component| glob_match(pattern, component))
+            }
+        })
+}
+
+// Match `text` against a glob where `*` is any run of characters and `?` any one
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None; // Position of the last `*` and the text it resumes from
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, resume)) => {
+                    p = star + 1;
+                    t = resume + 1;
+                    backtrack = Some((star, resume + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// Open a sanitized path and re-verify it through the file descriptor, closing the
+// window between canonicalize and open where the file could be swapped for a symlink.
+// O_NOFOLLOW refuses a symlinked final component, fstat confirms a regular file, and
+// where /proc is available the fd's real path must still lie within base_dir.
+fn open_verified(path: &Path, base_dir: &Path) -> io::Result<(File, Metadata)> {
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+        .map_err(|e| match e.raw_os_error() {
+            Some(libc::ELOOP) => io::Error::new(io::ErrorKind::NotFound, "symlink swapped in"),
+            _ => e,
+        })?;
+
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "not a regular file",
+        ));
+    }
+
+    let fd_path = PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd()));
+    if let Ok(real_path) = fs::read_link(fd_path) {
+        if !real_path.starts_with(base_dir) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "escaped base directory",
+            ));
+        }
+    }
+
+    Ok((file, metadata))
+}
+
+// Extract the path from a request target (RFC 7230 section 5.3), dropping any query.
+// The authority of an absolute-form target is ignored since we serve a single site.
+fn parse_request_target(target: &str) -> Option<&str> {
+    let path = if target.starts_with('/') {
+        target
+    } else {
+        let scheme_end = target.find("://")?;
+        let scheme = &target[..scheme_end];
+        if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+            return None;
+        }
+        let rest = &target[scheme_end + 3..];
+        let authority_end = rest.find(['/', '?']).unwrap_or(rest.len());
+        if authority_end == 0 {
+            return None;
+        }
+        match &rest[authority_end..] {
+            "" => "/",
+            path if path.starts_with('?') => "/",
+            path => path,
+        }
+    };
+
+    Some(path.split('?').next().unwrap_or(path))
+}
+
+// Collapse runs of '/' into a single separator
+fn merge_slashes(path: &str) -> String {
+    let mut merged = String::with_capacity(path.len());
+    for c in path.chars() {
+        if c != '/' || !merged.ends_with('/') {
+            merged.push(c);
+        }
+    }
+    merged
+}
+
+thread_local! {
+    // Per-worker xorshift state so workers never contend on a shared generator
+    static RNG_STATE: Cell<u64> = Cell::new({
+        let mut hasher = DefaultHasher::new();
+        thread::current().id().hash(&mut hasher);
+        SystemTime::now().hash(&mut hasher);
+        hasher.finish() | 1
+    });
+}
+
+// Next value from this worker's generator (not cryptographically secure)
+fn next_random() -> u64 {
+    RNG_STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        x
+    })
+}
+
+// Pick a uniformly distributed delay between min and max (inclusive)
+fn random_delay(min: Duration, max: Duration) -> Duration {
+    let random = next_random();
+    let span = max.saturating_sub(min).as_millis() as u64;
+    min + Duration::from_millis(random % (span + 1))
+}
+
+// Use the proxy's X-Request-Id when trusted and well-formed, otherwise a fresh id.
+// Incoming ids are limited to a safe charset so they cannot inject into logs.
+fn request_id(incoming: Option<&str>, trust_proxy: bool) -> String {
+    const MAX_REQUEST_ID_LEN: usize = 128;
+
+    match incoming.filter(|_| trust_proxy) {
+        Some(id)
+            if !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b)) =>
+        {
+            id.to_string()
+        }
+        _ => format!("{:016x}", next_random()),
+    }
+}
+
+// Check a request path against the probe prefixes, ignoring case
+fn is_probe(path: &str, patterns: &[String]) -> bool {
+    let path = decode(path).map_or_else(|_| path.to_lowercase(), |p| p.to_lowercase());
+    patterns
+        .iter()
+        .any(|pattern| path.starts_with(&pattern.to_lowercase()))
+}
+
+// Path of a served file relative to base_dir, for the X-Served-Path debug header.
+// Control and non-ASCII bytes are percent-encoded so the header stays well-formed.
+fn served_path(file_path: &Path, base_dir: &Path) -> String {
+    let relative = file_path.strip_prefix(base_dir).unwrap_or(file_path);
+    let mut encoded = String::from("/");
+    for byte in relative.to_string_lossy().bytes() {
+        if (0x20..0x7f).contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+// Content type for a served file. Unknown types fall back to application/octet-stream,
+// or get no Content-Type at all (letting the client sniff) with OMIT_FALLBACK_CONTENT_TYPE.
+// Text types without parameters get the configured charset.
+fn content_type_for(config: &Config, path: &Path) -> Option<String> {
+    match config.mime_types.content_type(path) {
+        Some(content_type)
+            if !config.charset.is_empty()
+                && !content_type.contains(';')
+                && is_textual(&content_type) =>
+        {
+            Some(format!("{}; charset={}", content_type, config.charset))
+        }
+        Some(content_type) => Some(content_type),
+        None if config.omit_fallback_content_type => None,
+        None => Some("application/octet-stream".to_string()),
+    }
+}
+
+// Check a path against the gone-list: exact entries, or prefixes ending in `*`
+fn is_gone(path: &str, gone_paths: &[String]) -> bool {
+    let path = decode(path).map_or_else(|_| path.to_string(), |p| p.into_owned());
+    gone_paths.iter().any(|gone| match gone.strip_suffix('*') {
+        Some(prefix) => path.starts_with(prefix),
+        None => path == *gone,
+    })
+}
+
+// Placeholder for access log fields that were never parsed
+fn or_dash(value: &str) -> &str {
+    if value.is_empty() {
+        "-"
+    } else {
+        value
+    }
+}
+
+// Find a request header by case-insensitive name
+fn header<'a>(headers: &[(&str, &'a str)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(field, _)| field.eq_ignore_ascii_case(name))
+        .map(|&(_, value)| value)
+}
+
+// Evaluate a single `bytes=` range (`start-end`, `start-` or `-suffix`).
+// Missing, malformed and multi-range headers fall back to the full file.
+fn parse_range(range: Option<&str>, size: u64) -> ByteRange {
+    let spec = match range.and_then(|range| range.trim().strip_prefix("bytes=")) {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Full,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return ByteRange::Full,
+    };
+
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", "") => return ByteRange::Full,
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(suffix) => (size.saturating_sub(suffix), size.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        },
+        (start, "") => match start.parse::<u64>() {
+            Ok(start) => (start, size.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        },
+        (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+            _ => return ByteRange::Full,
+        },
+    };
+
+    if size == 0 || start >= size {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial(start, end)
+    }
+}
+
+// Whether the file is unchanged since an If-Modified-Since date. HTTP dates have
+// one-second resolution, so sub-second mtimes are compared truncated. Malformed
+// dates are treated as if the header were absent.
+fn not_modified_since(since: Option<&str>, modified: Option<SystemTime>) -> bool {
+    let (Some(since), Some(modified)) = (since, modified) else {
+        return false;
+    };
+    let Ok(since) = httpdate::parse_http_date(since) else {
+        return false;
+    };
+    unix_secs(modified) <= unix_secs(since)
+}
+
+// Whole seconds since the Unix epoch, clamping earlier times to zero
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+// FNV-1a hash of a generated body; stable across restarts, unlike DefaultHasher
+fn content_hash(body: &[u8]) -> u64 {
+    body.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// Whether an If-None-Match list (`*` or comma-separated tags) matches the current
+// ETag. Uses weak comparison, so a `W/` prefix is ignored.
+fn etag_matches(if_none_match: &str, etag: Option<&str>) -> bool {
+    if if_none_match.trim() == "*" {
+        return true; // Any current representation matches
+    }
+    etag.is_some_and(|etag| {
+        if_none_match
+            .split(',')
+            .map(|tag| tag.trim())
+            .any(|tag| tag.strip_prefix("W/").unwrap_or(tag) == etag)
+    })
+}
+
+// Text-based types, which carry a charset and are worth compressing; images, video
+// and archives already are compressed
+fn is_textual(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("").trim();
+    essence.starts_with("text/")
+        || essence.ends_with("+xml")
+        || essence.ends_with("+json")
+        || matches!(
+            essence,
+            "application/javascript" | "application/json" | "application/xml"
+        )
+}
+
+fn is_compressible(content_type: Option<&str>) -> bool {
+    content_type.is_some_and(is_textual)
+}
+
+// Whether Accept-Encoding allows gzip, honoring `q=0` and the `*` wildcard
+fn accepts_gzip(accept_encoding: Option<&str>) -> bool {
+    let Some(accept_encoding) = accept_encoding else {
+        return false;
+    };
+    let quality = |coding: &str| {
+        accept_encoding.split(',').find_map(|entry| {
+            let mut params = entry.split(';');
+            if !params.next()?.trim().eq_ignore_ascii_case(coding) {
+                return None;
+            }
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some(q)
+        })
+    };
+    quality("gzip")
+        .or_else(|| quality("x-gzip"))
+        .or_else(|| quality("*"))
+        .is_some_and(|q| q > 0.0)
+}
+
+// Read a file of known size and gzip it in memory
+fn gzip_body(body: &mut impl Read, size: u64) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(
+        Vec::with_capacity(size as usize / 2),
+        Compression::default(),
     );
+    io::copy(&mut body.take(size), &mut encoder)?;
+    encoder.finish()
+}
+
+// Largest file read into memory for template substitution
+const MAX_TEMPLATE_SIZE: u64 = 1024 * 1024;
+
+// Whether a file is a text template per TEMPLATE_EXTENSIONS
+fn is_template(config: &Config, file_path: &Path, content_type: Option<&str>) -> bool {
+    let Some(extension) = file_path.extension().and_then(|ext| ext.to_str()) else {
+        return false;
+    };
+    content_type.is_some_and(|content_type| content_type.starts_with("text/"))
+        && config
+            .template_extensions
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(extension))
+}
+
+// Read a template file and substitute its placeholders. Returns None, with the file
+// rewound, when the contents are not UTF-8 and must be served unchanged.
+fn render_template(
+    file: &mut File,
+    size: u64,
+    vars: &[(String, String)],
+) -> io::Result<Option<Vec<u8>>> {
+    let mut contents = Vec::with_capacity(size as usize);
+    file.take(size).read_to_end(&mut contents)?;
+    match String::from_utf8(contents) {
+        Ok(text) => Ok(Some(template::render(&text, vars).into_bytes())),
+        Err(_) => {
+            file.rewind()?;
+            Ok(None)
+        }
+    }
+}
+
+// Extensions that mark a request as an asset, which must 404 rather than get HTML
+const ASSET_EXTENSIONS: &[&str] = &[
+    "js", "mjs", "css", "map", "json", "wasm", "png", "jpg", "jpeg", "gif", "svg", "webp", "avif",
+    "ico", "woff", "woff2", "ttf", "otf", "eot", "mp4", "webm", "mp3", "txt", "xml", "pdf", "zip",
+];
+
+// Whether a missing path looks like a client-side route: the client accepts HTML
+// and the last segment does not name an asset
+fn is_spa_route(path: &str, headers: &[(&str, &str)]) -> bool {
+    let wants_html = header(headers, "Accept").is_some_and(|accept| accept.contains("text/html"));
+    let last_segment = path.rsplit('/').next().unwrap_or("");
+    let is_asset = last_segment.rsplit_once('.').is_some_and(|(_, extension)| {
+        ASSET_EXTENSIONS
+            .iter()
+            .any(|asset| asset.eq_ignore_ascii_case(extension))
+    });
+    wants_html && !is_asset
+}
+
+// Ready when not draining for shutdown and the base directory is accessible
+fn is_ready(base_dir: &Path, running: &AtomicBool) -> bool {
+    running.load(Ordering::Relaxed) && fs::read_dir(base_dir).is_ok()
+}
+
+// Client connection that remembers what was sent, for stats
+struct Client {
+    stream: Box<dyn Stream>,
+    status: u16,
+    content_type: Option<String>,
+    bytes_sent: u64,
+    bandwidth_limit: Option<u64>,
+    max_transfer_time: Option<Duration>,
+    aborted: bool,      // Client disconnected before the body was fully sent
+    truncated: bool,    // Body cut off at MAX_TRANSFER_TIME
+    head_only: bool,    // HEAD request: send headers as for GET but never a body
+    keep_alive: bool,   // Leave the connection open after this response
+    request_id: String, // Echoed as X-Request-Id, empty until headers are parsed
+    peer: Option<IpAddr>,
+    method: String, // Request method and target for the access log
+    target: String,
+    version: String,
+    started: Instant,
+    compression: Option<(u64, u64)>, // Original and gzipped body size when compressed
+    requests: usize,                 // Requests read on this connection so far
+    route: &'static str,             // Routing rule that answered, "direct" for plain serving
+    buffer: Vec<u8>,                 // Bytes read past the previous request head
+    running: Arc<AtomicBool>,
+    drain_timeout: Option<Duration>,
+    drain_max_bytes: Option<u64>,
+    draining_since: Option<(Instant, u64)>, // When shutdown was noticed and bytes sent by then
+    connection_bytes: u64, // Bytes sent over the whole connection, for the drain allowance
+}
+
+impl Client {
+    // Once shutdown has started, check whether this connection used up its drain allowance
+    fn drain_exceeded(&mut self) -> bool {
+        if self.running.load(Ordering::Relaxed) {
+            return false;
+        }
+        let (since, bytes_before) = *self
+            .draining_since
+            .get_or_insert((Instant::now(), self.connection_bytes));
+        self.drain_timeout
+            .is_some_and(|timeout| since.elapsed() >= timeout)
+            || self
+                .drain_max_bytes
+                .is_some_and(|max| self.connection_bytes.saturating_sub(bytes_before) >= max)
+    }
+}
+
+// Largest file compressed in memory; bigger files are streamed uncompressed
+const MAX_GZIP_SIZE: u64 = 8 * 1024 * 1024;
+
+// Size of each read/write when streaming a body
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// How often an idle keep-alive connection checks whether shutdown has started
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// Copy `length` bytes of a body to the client in fixed-size chunks so memory stays
+// bounded, pacing writes to the client's bandwidth limit (bytes/sec) if set.
+// Nothing is written for HEAD requests.
+fn copy_body(client: &mut Client, body: &mut impl Read, length: u64) -> io::Result<()> {
+    if client.head_only {
+        return Ok(());
+    }
+
+    // With a limit, write roughly ten slices per second so pacing stays smooth
+    let chunk_size = match client.bandwidth_limit {
+        Some(limit) => (limit / 10).clamp(1024, CHUNK_SIZE as u64) as usize,
+        None => CHUNK_SIZE,
+    };
+    let mut buffer = vec![0; chunk_size.min(length as usize)];
+    let mut body = body.take(length);
+    let started = Instant::now();
+    let mut written = 0u64;
+
+    loop {
+        let n = match body.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        // Stop reading the body as soon as the client goes away
+        if let Err(e) = client.stream.write_all(&buffer[..n]) {
+            if matches!(
+                e.kind(),
+                io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+            ) {
+                client.aborted = true;
+                debug!(
+                    "Client disconnected after {} of {} body bytes",
+                    written, length
+                );
+            } else if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) {
+                debug!(
+                    "Write timed out after {} of {} body bytes, closing connection",
+                    written, length
+                );
+            }
+            return Err(e);
+        }
+        client.bytes_sent += n as u64;
+        client.connection_bytes += n as u64;
+        written += n as u64;
+
+        if written < length && client.drain_exceeded() {
+            eprintln!(
+                "Cut off {} during shutdown after {} of {} body bytes",
+                client
+                    .peer
+                    .map_or_else(|| "Unknown".to_string(), |ip| ip.to_string()),
+                written,
+                length
+            );
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "drain limit reached during shutdown",
+            ));
+        }
+
+        // Whatever was sent stays usable: files advertise ranges, so clients can resume
+        let over_budget = client
+            .max_transfer_time
+            .is_some_and(|max| client.started.elapsed() >= max);
+        if written < length && over_budget {
+            client.truncated = true;
+            debug!(
+                "Transfer time budget spent after {} of {} body bytes, closing connection",
+                written, length
+            );
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "transfer time budget exceeded",
+            ));
+        }
+
+        if let Some(limit) = client.bandwidth_limit {
+            let due = Duration::from_secs_f64(written as f64 / limit as f64);
+            if let Some(ahead) = due.checked_sub(started.elapsed()) {
+                thread::sleep(ahead);
+            }
+        }
+    }
+
+    if written < length {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "body ended before Content-Length",
+        ));
+    }
+    Ok(())
+}
+
+// Write the status line and headers, leaving out Content-Type entirely when there is none.
+// Without a Content-Length the body runs until the connection closes.
+fn write_headers(
+    client: &mut Client,
+    status: &str,
+    content_length: Option<u64>,
+    content_type: Option<&str>,
+    headers: &[(&str, String)],
+) -> io::Result<()> {
+    client.status = status
+        .split(' ')
+        .next()
+        .and_then(|code| code.parse().ok())
+        .unwrap_or(0);
+    client.content_type = content_type.map(str::to_string);
+
+    let mut response_headers = format!("HTTP/1.1 {}\r\n", status);
+    if let Some(content_type) = content_type {
+        response_headers.push_str(&format!("Content-Type: {}\r\n", content_type));
+    }
+    match content_length {
+        _ if client.status == 204 || client.status == 304 => {}
+        Some(length) => {
+            response_headers.push_str(&format!("Content-Length: {}\r\n", length));
+        }
+        None => client.keep_alive = false,
+    }
+    for (name, value) in headers {
+        response_headers.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if !client.request_id.is_empty() {
+        response_headers.push_str(&format!("X-Request-Id: {}\r\n", client.request_id));
+    }
+    let connection = if client.keep_alive {
+        "keep-alive"
+    } else {
+        "close"
+    };
+    response_headers.push_str(&format!("Connection: {}\r\n\r\n", connection));
+
+    client.stream.write_all(response_headers.as_bytes())?;
+    client.bytes_sent += response_headers.len() as u64;
+    client.connection_bytes += response_headers.len() as u64;
+    Ok(())
+}
+
+// Send an HTTP response
+fn send_response(
+    client: &mut Client,
+    status: &str,
+    content: Option<&[u8]>,
+    content_type: Option<&str>,
+    headers: &[(&str, String)],
+) {
+    let content_length = content.map_or(0, |c| c.len() as u64);
 
     // Write headers to the client
-    if let Err(e) = stream.write_all(response_headers.as_bytes()) {
+    let length = Some(content_length);
+    if let Err(e) = write_headers(client, status, length, content_type, headers) {
         eprintln!("Failed to send response headers: {}", e);
+        client.keep_alive = false;
         return;
     }
 
     // Write content if available
-    if let Some(body) = content {
-        if let Err(e) = stream.write_all(body) {
+    if let Some(mut body) = content {
+        if let Err(e) = copy_body(client, &mut body, content_length) {
             eprintln!("Failed to send response body: {}", e);
+            client.keep_alive = false;
+        }
+    }
+}
+
+// Send a file as the response body, streaming it instead of loading it into memory
+fn send_file(
+    client: &mut Client,
+    status: &str,
+    file: &mut File,
+    length: u64,
+    content_type: Option<&str>,
+    headers: &[(&str, String)],
+) {
+    if let Err(e) = write_headers(client, status, Some(length), content_type, headers) {
+        eprintln!("Failed to send response headers: {}", e);
+        client.keep_alive = false;
+        return;
+    }
+
+    if let Err(e) = copy_body(client, file, length) {
+        eprintln!("Failed to send response body: {}", e);
+        client.keep_alive = false;
+    }
+}
+
+// Open the HTML page for a 404 or 500: ERROR_404/ERROR_500 if set, else 404.html or
+// 500.html in base_dir. Pages resolve like requests, so they cannot escape base_dir.
+fn error_page(config: &Config, base_dir: &Path, status: &str) -> Option<(File, u64)> {
+    let code = status.split(' ').next()?;
+    let page = match code {
+        "404" => config.error_404.as_deref(),
+        "500" => config.error_500.as_deref(),
+        _ => return None,
+    };
+    let page = match page {
+        Some(page) => page.to_string(),
+        // Most sites have no error pages, skip the lookup (and its log line) quietly
+        None if !base_dir.join(format!("{}.html", code)).exists() => return None,
+        None => format!("/{}.html", code),
+    };
+
+    let file_path = sanitize_path(base_dir, &page, config).ok()?;
+    let (file, metadata) = open_verified(&file_path, base_dir).ok()?;
+    Some((file, metadata.len()))
+}
+
+// Send an error response. Paths under a JSON_ERROR_PREFIXES entry get a JSON body,
+// configured or conventional HTML pages are used for 404 and 500, and DEBUG_ERRORS
+// adds a short diagnostic otherwise. Diagnostics are fixed strings so client
+// input is never reflected back.
+fn send_error(
+    client: &mut Client,
+    config: &Config,
+    base_dir: &Path,
+    status: &str,
+    path: &str,
+    diagnostic: Option<&'static str>,
+) {
+    send_error_with(client, config, base_dir, status, path, diagnostic, &[]);
+}
+
+// Send an error response like send_error, with extra headers such as Allow,
+// Retry-After or Content-Range that the status calls for
+fn send_error_with(
+    client: &mut Client,
+    config: &Config,
+    base_dir: &Path,
+    status: &str,
+    path: &str,
+    diagnostic: Option<&'static str>,
+    headers: &[(&str, String)],
+) {
+    let diagnostic = diagnostic.filter(|_| config.debug_errors);
+    let wants_json = config
+        .json_error_prefixes
+        .iter()
+        .any(|prefix| path.starts_with(prefix.as_str()));
+
+    if wants_json {
+        let (code, reason) = status.split_once(' ').unwrap_or((status, ""));
+        let body = match diagnostic {
+            Some(detail) => format!(
+                "{{\"status\":{},\"error\":\"{}\",\"detail\":\"{}\"}}",
+                code, reason, detail
+            ),
+            None => format!("{{\"status\":{},\"error\":\"{}\"}}", code, reason),
+        };
+        send_response(
+            client,
+            status,
+            Some(body.as_bytes()),
+            Some("application/json"),
+            headers,
+        );
+    } else if let Some((mut page, length)) =
+        error_page(config, base_dir, status).filter(|_| !config.dry_serve)
+    {
+        let content_type = Some("text/html; charset=utf-8");
+        send_file(client, status, &mut page, length, content_type, headers);
+    } else if let Some(detail) = diagnostic {
+        let body = format!("{}: {}\n", status, detail);
+        send_response(
+            client,
+            status,
+            Some(body.as_bytes()),
+            Some("text/plain"),
+            headers,
+        );
+    } else {
+        send_response(client, status, None, Some("text/plain"), headers);
+    }
+}
+
+// Turn away a connection before it reaches a worker: `status`, a Retry-After and
+// close. This runs on the accept loop, so the write never blocks; TLS connections
+// are simply closed.
+pub fn reject_connection(
+    mut stream: TcpStream,
+    tls: bool,
+    status: &str,
+    retry_after: Duration,
+    stats: &Stats,
+) {
+    if tls || stream.set_nonblocking(true).is_err() {
+        return;
+    }
+    let response = format!(
+        "HTTP/1.1 {}\r\nRetry-After: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status,
+        retry_after.as_secs_f64().ceil().max(1.0) as u64
+    );
+    if stream.write_all(response.as_bytes()).is_ok() {
+        let code = status.split(' ').next().and_then(|code| code.parse().ok());
+        stats.record_response(code.unwrap_or(0), response.len() as u64, None);
+    }
+    debug!("Rejected connection with {}", status);
+}
+
+// A connection on its way to a pool worker. If the job is dropped without running,
+// because the queue was full or the pool is gone, the client still gets a 503.
+pub struct QueuedConnection {
+    stream: Option<TcpStream>,
+    tls: bool,
+    stats: Arc<Stats>,
+}
+
+impl QueuedConnection {
+    pub fn new(stream: TcpStream, tls: bool, stats: Arc<Stats>) -> Self {
+        Self {
+            stream: Some(stream),
+            tls,
+            stats,
+        }
+    }
+
+    // Claim the stream once a worker picks the job up
+    pub fn take(mut self) -> Option<TcpStream> {
+        self.stream.take()
+    }
+}
+
+impl Drop for QueuedConnection {
+    fn drop(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            let status = "503 Service Unavailable";
+            reject_connection(
+                stream,
+                self.tls,
+                status,
+                Duration::from_secs(1),
+                &self.stats,
+            );
         }
     }
 }
 
-// Handle a single HTTP request
-pub fn handle_client(mut stream: TcpStream, base_dir: Arc<PathBuf>, index_file: &str) {
-    println!(
+// Handle a single HTTP connection
+pub fn handle_client(
+    stream: TcpStream,
+    tls: Option<Arc<ServerConfig>>,
+    base_dir: Arc<PathBuf>,
+    config: Arc<Config>,
+    running: Arc<AtomicBool>,
+    stats: Arc<Stats>,
+) {
+    let peer_addr = stream.peer_addr().ok();
+    let peer = peer_addr.map(|addr| addr.ip());
+    debug!(
         "Connection from: {}",
-        stream
-            .peer_addr()
-            .map(|addr| addr.to_string())
-            .unwrap_or_else(|_| "Unknown".to_string())
+        peer_addr.map_or_else(|| "Unknown".to_string(), |addr| addr.to_string())
     );
 
-    let mut buffer = [0; 4096];
-    let bytes_read = match stream.read(&mut buffer) {
-        Ok(0) => return, // Client closed connection
-        Ok(n) => n,
+    // Accepted sockets inherit O_NONBLOCK from the listener on some platforms (BSD, macOS),
+    // which would make write_all fail with WouldBlock partway through large bodies
+    if let Err(e) = stream.set_nonblocking(false) {
+        eprintln!("Failed to set stream to blocking mode: {}", e);
+        return;
+    }
+
+    // Bound every read and write so a stalled client cannot hold the worker
+    if let Err(e) = stream
+        .set_read_timeout(config.read_timeout)
+        .and_then(|_| stream.set_write_timeout(config.write_timeout))
+    {
+        eprintln!("Failed to set socket timeouts: {}", e);
+        return;
+    }
+
+    if config.tcp_keepalive {
+        let keepalive = TcpKeepalive::new()
+            .with_time(config.tcp_keepalive_idle)
+            .with_interval(config.tcp_keepalive_interval)
+            .with_retries(config.tcp_keepalive_retries);
+        if let Err(e) = SockRef::from(&stream).set_tcp_keepalive(&keepalive) {
+            eprintln!("Failed to enable TCP keepalive: {}", e);
+        }
+    }
+
+    // TLS handshakes happen lazily on the first read, under the read timeout
+    let stream: Box<dyn Stream> = match tls {
+        Some(tls) => match ServerConnection::new(tls) {
+            Ok(connection) => Box::new(StreamOwned::new(connection, stream)),
+            Err(e) => {
+                eprintln!("Failed to start TLS session: {}", e);
+                return;
+            }
+        },
+        None => Box::new(stream),
+    };
+
+    stats.busy_workers.fetch_add(1, Ordering::Relaxed);
+    let mut client = Client {
+        stream,
+        status: 0,
+        content_type: None,
+        bytes_sent: 0,
+        bandwidth_limit: config.bandwidth_limit,
+        max_transfer_time: config.max_transfer_time,
+        aborted: false,
+        truncated: false,
+        head_only: false,
+        keep_alive: false,
+        request_id: String::new(),
+        peer,
+        method: String::new(),
+        target: String::new(),
+        version: String::new(),
+        started: Instant::now(),
+        compression: None,
+        requests: 0,
+        route: "direct",
+        buffer: Vec::new(),
+        running: running.clone(),
+        drain_timeout: config.drain_timeout,
+        drain_max_bytes: config.drain_max_bytes,
+        draining_since: None,
+        connection_bytes: 0,
+    };
+    loop {
+        client.requests += 1;
+        client.started = Instant::now();
+        serve_request(&mut client, &base_dir, &config, &running, &stats);
+
+        if client.status == 0 {
+            break; // Nothing was answered, the connection is done
+        }
+        debug!("Matched rule: {}", client.route);
+        log::access(
+            config.log_format,
+            &log::Access {
+                client: client.peer,
+                method: or_dash(&client.method),
+                path: or_dash(&client.target),
+                version: or_dash(&client.version),
+                status: client.status,
+                bytes_sent: client.bytes_sent,
+                duration: client.started.elapsed(),
+                request_id: &client.request_id,
+                compression: client.compression,
+                route: config.routes_logged().then_some(client.route),
+                keep_alive: client.keep_alive && !client.aborted && !client.truncated,
+            },
+        );
+        let content_type = client.content_type.as_deref();
+        stats.record_response(client.status, client.bytes_sent, content_type);
+        if client.aborted {
+            stats.record_abort(client.bytes_sent);
+        }
+        if client.truncated {
+            stats.truncated_transfers.fetch_add(1, Ordering::Relaxed);
+        }
+        if !client.keep_alive || client.aborted || client.truncated {
+            break;
+        }
+
+        // Reset per-response state and wait a bounded time for the next request
+        client.status = 0;
+        client.content_type = None;
+        client.bytes_sent = 0;
+        client.head_only = false;
+        client.keep_alive = false;
+        client.truncated = false;
+        client.request_id.clear();
+        client.compression = None;
+        client.route = "direct";
+        client.method.clear();
+        client.target.clear();
+        client.version.clear();
+
+        // Only the idle wait gets KEEP_ALIVE_TIMEOUT; once the next request starts, its
+        // headers and body are read under READ_TIMEOUT again. The wait is sliced so an
+        // idle connection is closed promptly once shutdown starts.
+        if client.buffer.is_empty() {
+            let idle_since = Instant::now();
+            let mut chunk = [0; 4096];
+            let n = loop {
+                if !client.running.load(Ordering::Relaxed) {
+                    break Err(io::Error::other("server shutting down"));
+                }
+                let remaining = config
+                    .keep_alive_timeout
+                    .saturating_sub(idle_since.elapsed());
+                if remaining.is_zero() {
+                    break Err(io::ErrorKind::TimedOut.into());
+                }
+                let socket = client.stream.socket();
+                if let Err(e) = socket.set_read_timeout(Some(remaining.min(IDLE_POLL_INTERVAL))) {
+                    break Err(e);
+                }
+                match client.stream.read(&mut chunk) {
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            io::ErrorKind::Interrupted
+                                | io::ErrorKind::WouldBlock
+                                | io::ErrorKind::TimedOut
+                        ) => {}
+                    result => break result,
+                }
+            };
+            match n {
+                Ok(0) => break, // Client closed the idle connection
+                Ok(n) => client.buffer.extend_from_slice(&chunk[..n]),
+                Err(e) => {
+                    debug!("Keep-alive connection ended while idle: {}", e);
+                    break;
+                }
+            }
+            if let Err(e) = client.stream.socket().set_read_timeout(config.read_timeout) {
+                eprintln!("Failed to restore read timeout: {}", e);
+                break;
+            }
+        }
+    }
+    client.stream.close();
+    stats.busy_workers.fetch_sub(1, Ordering::Relaxed);
+}
+
+// Read until the blank line ending the headers, which may span several TCP segments
+// or already be sitting in `buffer` from a pipelined read. Returns the length of the
+// head, None if the client closed before sending anything, and InvalidData once
+// `max_size` bytes arrive without the terminator.
+fn read_head(
+    stream: &mut impl Read,
+    buffer: &mut Vec<u8>,
+    max_size: usize,
+) -> io::Result<Option<usize>> {
+    let mut chunk = [0; 4096];
+    let mut scanned = 0usize;
+    loop {
+        // Only rescan the tail that could complete a terminator
+        let scan_from = scanned.saturating_sub(3);
+        let window = &buffer[scan_from..];
+        let crlf = window
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .map(|i| i + 4);
+        let lf = window.windows(2).position(|w| w == b"\n\n").map(|i| i + 2);
+        let head_end = crlf.into_iter().chain(lf).min().map(|end| scan_from + end);
+
+        match head_end {
+            Some(end) if end <= max_size => return Ok(Some(end)),
+            _ if buffer.len() >= max_size => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "request headers too large",
+                ))
+            }
+            _ => {}
+        }
+        scanned = buffer.len();
+
+        let n = match stream.read(&mut chunk) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if n == 0 {
+            // Parse whatever arrived before the client stopped sending
+            return Ok((!buffer.is_empty()).then_some(buffer.len()));
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+    }
+}
+
+// Send a body generated in memory, gzipped under the same rules as files
+fn serve_generated(
+    client: &mut Client,
+    config: &Config,
+    body: Vec<u8>,
+    content_type: Option<&str>,
+    request_headers: &[(&str, &str)],
+    mut headers: Vec<(&str, String)>,
+) {
+    let compressible = config.gzip && is_compressible(content_type);
+    if compressible {
+        headers.push(("Vary", "Accept-Encoding".to_string()));
+    }
+    let size = body.len() as u64;
+    let gzip = compressible
+        && size >= config.gzip_min_size
+        && accepts_gzip(header(request_headers, "Accept-Encoding"));
+
+    // Strong validator from the content itself, for bodies small enough to hash.
+    // Larger bodies get no ETag at all: a generated body has no mtime to build a weak
+    // tag from, and one from a sample of the content would 304 on mid-body changes.
+    if size > 0 && size <= config.generated_etag_max_size {
+        let suffix = if gzip { "-gzip" } else { "" };
+        let etag = format!("\"{:016x}{}\"", content_hash(&body), suffix);
+        let if_none_match = header(request_headers, "If-None-Match");
+        let unchanged = if_none_match.is_some_and(|tags| etag_matches(tags, Some(&etag)));
+        headers.push(("ETag", etag));
+        if unchanged {
+            send_response(client, "304 Not Modified", None, None, &headers);
+            debug!("Responded with 304 Not Modified");
+            return;
+        }
+    }
+
+    if gzip {
+        match gzip_body(&mut body.as_slice(), size) {
+            Ok(compressed) => {
+                debug!("Compressed {} bytes to {}", size, compressed.len());
+                client.compression = Some((size, compressed.len() as u64));
+                headers.push(("Content-Encoding", "gzip".to_string()));
+                send_response(client, "200 OK", Some(&compressed), content_type, &headers);
+                debug!("Responded with 200 OK");
+                return;
+            }
+            Err(e) => {
+                eprintln!("Failed to compress response, sending uncompressed: {}", e);
+                headers.retain(|&(name, _)| name != "ETag"); // Tag named the gzip variant
+            }
+        }
+    }
+
+    send_response(client, "200 OK", Some(&body), content_type, &headers);
+    debug!("Responded with 200 OK");
+}
+
+// Audit mode: report which file a request maps to and answer without its contents
+fn dry_serve(client: &mut Client, config: &Config, base_dir: &Path, path: &str, file_path: &Path) {
+    match open_verified(file_path, base_dir) {
+        Ok(_) => {
+            let content_type = content_type_for(config, file_path);
+            println!(
+                "DRY-SERVE: {} -> {:?} ({})",
+                path,
+                file_path,
+                content_type.as_deref().unwrap_or("no content type")
+            );
+            send_response(client, "200 OK", None, content_type.as_deref(), &[]);
+            debug!("Responded with 200 OK");
+        }
+        Err(e) => {
+            println!("DRY-SERVE: {} -> not servable ({})", path, e);
+            send_error(client, config, base_dir, "404 Not Found", path, None);
+            debug!("Responded with 404 Not Found");
+        }
+    }
+}
+
+// Whether a whole file is read into memory before sending rather than streamed in
+// chunks. Files up to BUFFER_MAX_SIZE are buffered; STREAM_EXTENSIONS and
+// BUFFER_EXTENSIONS override that per extension, though never past MAX_GZIP_SIZE.
+fn should_buffer(config: &Config, file_path: &Path, size: u64) -> bool {
+    let extension = file_path
+        .extension()
+        .and_then(|extension| extension.to_str());
+    let listed = |extensions: &[String]| {
+        extension.is_some_and(|extension| {
+            extensions.iter().any(|listed| {
+                listed
+                    .trim_start_matches('.')
+                    .eq_ignore_ascii_case(extension)
+            })
+        })
+    };
+    if listed(&config.stream_extensions) {
+        false
+    } else if listed(&config.buffer_extensions) {
+        size <= MAX_GZIP_SIZE
+    } else {
+        size <= config.buffer_max_size
+    }
+}
+
+// Serve a sanitized file, honoring a single byte range if one was requested
+fn serve_file(
+    client: &mut Client,
+    config: &Config,
+    base_dir: &Path,
+    path: &str,
+    file_path: &Path,
+    request_headers: &[(&str, &str)],
+) {
+    let (mut file, metadata) = match open_verified(file_path, base_dir) {
+        Ok(opened) => opened,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            eprintln!("File changed after validation: {}", e);
+            send_error(client, config, base_dir, "404 Not Found", path, None);
+            debug!("Responded with 404 Not Found");
+            return;
+        }
+        Err(_) => {
+            let status = "500 Internal Server Error";
+            send_error(client, config, base_dir, status, path, None);
+            debug!("Responded with 500 Internal Server Error");
+            return;
+        }
+    };
+
+    let content_type = content_type_for(config, file_path);
+    let content_type = content_type.as_deref();
+    let size = metadata.len();
+    let mut headers = Vec::new();
+    if config.debug {
+        headers.push(("X-Served-Path", served_path(file_path, base_dir)));
+    }
+
+    // Templates are regenerated per request, so they skip file validators and ranges
+    if size <= MAX_TEMPLATE_SIZE && is_template(config, file_path, content_type) {
+        match render_template(&mut file, size, &config.template_vars) {
+            Ok(Some(body)) => {
+                serve_generated(client, config, body, content_type, request_headers, headers);
+                return;
+            }
+            Ok(None) => {} // Not text after all, serve the file as is
+            Err(e) => {
+                eprintln!("Failed to render template: {}", e);
+                let status = "500 Internal Server Error";
+                send_error(client, config, base_dir, status, path, None);
+                debug!("Responded with 500 Internal Server Error");
+                return;
+            }
+        }
+    }
+    headers.push(("Accept-Ranges", "bytes".to_string()));
+
+    // Filesystems without mtime support simply get no Last-Modified
+    let modified = metadata.modified().ok();
+    if let Some(modified) = modified {
+        headers.push(("Last-Modified", httpdate::fmt_http_date(modified)));
+    }
+    // Range requests are served from the uncompressed file
+    let compressible = config.gzip && is_compressible(content_type);
+    if compressible {
+        headers.push(("Vary", "Accept-Encoding".to_string()));
+    }
+    let gzip = compressible
+        && (config.gzip_min_size..=MAX_GZIP_SIZE).contains(&size)
+        && header(request_headers, "Range").is_none()
+        && accepts_gzip(header(request_headers, "Accept-Encoding"));
+
+    // The gzip representation gets its own validator
+    let etag = modified.map(|modified| {
+        let suffix = if gzip { "-gzip" } else { "" };
+        format!("\"{}-{}{}\"", size, unix_secs(modified), suffix)
+    });
+    if let Some(etag) = &etag {
+        headers.push(("ETag", etag.clone()));
+    }
+
+    // If-None-Match takes precedence over If-Modified-Since when both are sent
+    let unchanged = match header(request_headers, "If-None-Match") {
+        Some(if_none_match) => etag_matches(if_none_match, etag.as_deref()),
+        None => not_modified_since(header(request_headers, "If-Modified-Since"), modified),
+    };
+    if unchanged {
+        send_response(client, "304 Not Modified", None, None, &headers);
+        debug!("Responded with 304 Not Modified");
+        return;
+    }
+
+    if gzip {
+        match gzip_body(&mut file, size) {
+            Ok(compressed) => {
+                debug!("Compressed {} bytes to {}", size, compressed.len());
+                client.compression = Some((size, compressed.len() as u64));
+                headers.push(("Content-Encoding", "gzip".to_string()));
+                send_response(client, "200 OK", Some(&compressed), content_type, &headers);
+                debug!("Responded with 200 OK");
+            }
+            Err(e) => {
+                eprintln!("Failed to compress file: {}", e);
+                let status = "500 Internal Server Error";
+                send_error(client, config, base_dir, status, path, None);
+                debug!("Responded with 500 Internal Server Error");
+            }
+        }
+        return;
+    }
+
+    // Send response
+    match parse_range(header(request_headers, "Range"), size) {
+        // Buffered files are read up front, so a read error is still a clean 500
+        ByteRange::Full if !client.head_only && should_buffer(config, file_path, size) => {
+            let mut body = Vec::with_capacity(size as usize);
+            match file.take(size).read_to_end(&mut body) {
+                Ok(_) => {
+                    send_response(client, "200 OK", Some(&body), content_type, &headers);
+                    debug!("Responded with 200 OK (buffered)");
+                }
+                Err(e) => {
+                    eprintln!("Failed to read file: {}", e);
+                    let status = "500 Internal Server Error";
+                    send_error(client, config, base_dir, status, path, None);
+                    debug!("Responded with 500 Internal Server Error");
+                }
+            }
+        }
+        ByteRange::Full => {
+            send_file(client, "200 OK", &mut file, size, content_type, &headers);
+            debug!("Responded with 200 OK");
+        }
+        ByteRange::Partial(start, end) => {
+            if let Err(e) = file.seek(SeekFrom::Start(start)) {
+                eprintln!("Failed to seek file: {}", e);
+                let status = "500 Internal Server Error";
+                send_error(client, config, base_dir, status, path, None);
+                debug!("Responded with 500 Internal Server Error");
+                return;
+            }
+            let content_range = format!("bytes {}-{}/{}", start, end, size);
+            headers.push(("Content-Range", content_range));
+            let length = end - start + 1;
+            let status = "206 Partial Content";
+            send_file(client, status, &mut file, length, content_type, &headers);
+            debug!("Responded with 206 Partial Content");
+        }
+        ByteRange::Unsatisfiable => {
+            headers.push(("Content-Range", format!("bytes */{}", size)));
+            let status = "416 Range Not Satisfiable";
+            send_error_with(client, config, base_dir, status, path, None, &headers);
+            debug!("Responded with 416 Range Not Satisfiable");
+        }
+    }
+}
+
+// Headers that describe one connection and are never forwarded by a proxy (RFC 9110 7.6.1)
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Upgrade",
+];
+
+// Upper bound on connecting to the proxy upstream
+const PROXY_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Whether a header belongs to the connection it arrived on: a standard hop-by-hop
+// header or one named in that message's Connection header
+fn is_hop_by_hop(name: &str, headers: &[(&str, &str)]) -> bool {
+    HOP_BY_HOP_HEADERS
+        .iter()
+        .any(|hop| hop.eq_ignore_ascii_case(name))
+        || header(headers, "Connection").is_some_and(|connection| {
+            connection
+                .split(',')
+                .any(|token| token.trim().eq_ignore_ascii_case(name))
+        })
+}
+
+// Forward a GET or HEAD to PROXY_UPSTREAM and stream its response back. The upstream
+// connection carries this one request; a response without a Content-Length is
+// relayed until the upstream closes, which ends the client connection too.
+fn proxy_request(
+    client: &mut Client,
+    config: &Config,
+    base_dir: &Path,
+    path: &str,
+    headers: &[(&str, &str)],
+) {
+    let Some((host, addr)) = &config.proxy_upstream else {
+        return;
+    };
+    let bad_gateway = |client: &mut Client, diagnostic: &'static str| {
+        send_error(
+            client,
+            config,
+            base_dir,
+            "502 Bad Gateway",
+            path,
+            Some(diagnostic),
+        );
+        debug!("Responded with 502 Bad Gateway");
+    };
+
+    let mut upstream = match TcpStream::connect_timeout(addr, PROXY_CONNECT_TIMEOUT) {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            eprintln!("Failed to connect to upstream {}: {}", host, e);
+            bad_gateway(client, "Upstream unreachable");
+            return;
+        }
+    };
+    if let Err(e) = upstream
+        .set_read_timeout(config.read_timeout)
+        .and_then(|_| upstream.set_write_timeout(config.write_timeout))
+    {
+        eprintln!("Failed to set upstream timeouts: {}", e);
+    }
+
+    // Same path and query, the client's end-to-end headers, and who it came from
+    let query = client.target.find('?').map_or("", |i| &client.target[i..]);
+    let mut request = format!(
+        "{} {}{} HTTP/1.1\r\nHost: {}\r\n",
+        client.method, path, query, host
+    );
+    // Any request body was already drained, so its framing headers must not be forwarded
+    let replaced = [
+        "Host",
+        "X-Forwarded-For",
+        "X-Forwarded-Host",
+        "X-Request-Id",
+        "Content-Length",
+        "Transfer-Encoding",
+    ];
+    for (name, value) in headers {
+        if !is_hop_by_hop(name, headers) && !replaced.iter().any(|r| r.eq_ignore_ascii_case(name)) {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    if let Some(peer) = client.peer {
+        let forwarded_for = match header(headers, "X-Forwarded-For") {
+            Some(chain) if config.trust_proxy => format!("{}, {}", chain, peer),
+            _ => peer.to_string(),
+        };
+        request.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded_for));
+    }
+    if let Some(original_host) = header(headers, "Host") {
+        request.push_str(&format!("X-Forwarded-Host: {}\r\n", original_host));
+    }
+    request.push_str(&format!(
+        "X-Request-Id: {}\r\nConnection: close\r\n\r\n",
+        client.request_id
+    ));
+    if let Err(e) = upstream.write_all(request.as_bytes()) {
+        eprintln!("Failed to send request to upstream {}: {}", host, e);
+        bad_gateway(client, "Upstream write failed");
+        return;
+    }
+
+    let mut buffer = Vec::new();
+    let head_end = match read_head(&mut upstream, &mut buffer, config.max_header_size) {
+        Ok(Some(head_end)) => head_end,
+        Ok(None) => {
+            eprintln!("Upstream {} closed without a response", host);
+            bad_gateway(client, "Empty upstream response");
+            return;
+        }
+        Err(e) => {
+            eprintln!("Failed to read response from upstream {}: {}", host, e);
+            bad_gateway(client, "Upstream read failed");
+            return;
+        }
+    };
+
+    // "HTTP/1.x 200 OK" -> "200 OK"
+    let head = String::from_utf8_lossy(&buffer[..head_end]).into_owned();
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .and_then(|line| line.strip_prefix("HTTP/1."))
+        .and_then(|line| line.get(2..))
+        .map(str::trim)
+        .filter(|status| status.split(' ').next().is_some_and(|code| code.len() == 3));
+    let Some(status) = status else {
+        eprintln!("Malformed status line from upstream {}", host);
+        bad_gateway(client, "Malformed upstream response");
+        return;
+    };
+    let upstream_headers: Vec<(&str, &str)> = lines
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim(), value.trim()))
+        .collect();
+
+    // Chunked bodies are relayed untouched, so they too end with the upstream connection
+    let chunked = header(&upstream_headers, "Transfer-Encoding").is_some();
+    let length = header(&upstream_headers, "Content-Length")
+        .and_then(|length| length.parse::<u64>().ok())
+        .filter(|_| !chunked);
+    let content_type = header(&upstream_headers, "Content-Type");
+    let own = ["Content-Length", "Content-Type", "X-Request-Id"];
+    let response_headers: Vec<(&str, String)> = upstream_headers
+        .iter()
+        .filter(|(name, _)| {
+            !is_hop_by_hop(name, &upstream_headers)
+                && !own.iter().any(|own| own.eq_ignore_ascii_case(name))
+        })
+        .map(|(name, value)| (*name, value.to_string()))
+        .collect();
+
+    if let Err(e) = write_headers(client, status, length, content_type, &response_headers) {
+        eprintln!("Failed to send response headers: {}", e);
+        client.keep_alive = false;
+        return;
+    }
+    debug!("Proxied {} to {}: {}", path, host, status);
+    if client.status == 204 || client.status == 304 {
+        return;
+    }
+
+    let mut body = (&buffer[head_end..]).chain(&mut upstream);
+    match copy_body(client, &mut body, length.unwrap_or(u64::MAX)) {
+        Ok(()) => {}
+        Err(e) if length.is_none() && e.kind() == io::ErrorKind::UnexpectedEof => {}
+        Err(e) => {
+            eprintln!("Failed to relay upstream response body: {}", e);
+            client.keep_alive = false;
+        }
+    }
+}
+
+// Read and throw away a request body of `length` bytes, starting with any of it
+// already buffered alongside the head
+fn discard_body(client: &mut Client, length: u64) -> io::Result<()> {
+    let buffered = client.buffer.len().min(length as usize);
+    client.buffer.drain(..buffered);
+    let remaining = length - buffered as u64;
+    let read = io::copy(&mut (&mut client.stream).take(remaining), &mut io::sink())?;
+    if read < remaining {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "body ended before Content-Length",
+        ));
+    }
+    Ok(())
+}
+
+// Read, route and answer a single HTTP request
+fn serve_request(
+    client: &mut Client,
+    base_dir: &Path,
+    config: &Config,
+    running: &AtomicBool,
+    stats: &Stats,
+) {
+    let head_end = match read_head(
+        &mut client.stream,
+        &mut client.buffer,
+        config.max_header_size,
+    ) {
+        Ok(Some(head_end)) => head_end,
+        Ok(None) => return, // Client closed connection
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            let status = "431 Request Header Fields Too Large";
+            send_error(client, config, base_dir, status, "", None);
+            debug!("Responded with 431 Request Header Fields Too Large");
+            return;
+        }
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            debug!("Read timed out, closing connection");
+            return;
+        }
         Err(e) => {
             eprintln!("Failed to read from stream: {}", e);
             return;
         }
     };
 
-    let request = String::from_utf8_lossy(&buffer[..bytes_read]);
+    // Testing aid: delay the response by a random amount
+    if let Some((min, max)) = config.response_jitter {
+        thread::sleep(random_delay(min, max));
+    }
+
+    // Anything after the head stays buffered for the next pipelined request
+    let head: Vec<u8> = client.buffer.drain(..head_end).collect();
+    let request = String::from_utf8_lossy(&head);
     let mut lines = request.lines();
 
     // Parse the first request line
     let request_line = match lines.next() {
         Some(line) => line,
         None => {
-            send_response(&mut stream, "400 Bad Request", None, "text/plain");
+            send_error(
+                client,
+                config,
+                base_dir,
+                "400 Bad Request",
+                "",
+                Some("Empty request"),
+            );
             return;
         }
     };
 
+    // Collect header fields up to the blank line
+    let headers: Vec<(&str, &str)> = lines
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim(), value.trim()))
+        .collect();
+    client.request_id = request_id(header(&headers, "X-Request-Id"), config.trust_proxy);
+
     let mut parts = request_line.split_whitespace();
     let method = parts.next();
     let path = parts.next();
     let http_version = parts.next();
+    client.method = method.unwrap_or_default().to_string();
+    client.target = path.unwrap_or_default().to_string();
+    client.version = http_version.unwrap_or_default().to_string();
 
     // Validate request structure
-    if method != Some("GET") || path.is_none() || http_version != Some("HTTP/1.1") {
-        send_response(&mut stream, "400 Bad Request", None, "text/plain");
+    let (method, path) = match (method, path, http_version, parts.next()) {
+        (Some(method), Some(path), Some(_), None) => (method, path),
+        _ => {
+            let diagnostic = Some("Malformed request line");
+            send_error(client, config, base_dir, "400 Bad Request", "", diagnostic);
+            return;
+        }
+    };
+    if http_version != Some("HTTP/1.1") {
+        let diagnostic = Some("Unsupported HTTP version");
+        send_error(
+            client,
+            config,
+            base_dir,
+            "400 Bad Request",
+            path,
+            diagnostic,
+        );
         return;
     }
 
-    let path = path.unwrap_or_else(|| "/");
-    println!("Requested path: {}", path);
+    // None of the supported methods takes a body. REJECT_REQUEST_BODY answers one with
+    // 400; otherwise bodies up to BODY_DRAIN_MAX are read and discarded so the next
+    // request on the connection starts in the right place. Anything else (chunked,
+    // oversized or unparseable) is left unread and the connection closed after replying.
+    let chunked = header(&headers, "Transfer-Encoding").is_some();
+    let body_length = header(&headers, "Content-Length")
+        .map_or(Some(0), |length| length.trim().parse::<u64>().ok());
+    if config.reject_request_body && (chunked || body_length != Some(0)) {
+        let diagnostic = Some("Unexpected request body");
+        send_error(
+            client,
+            config,
+            base_dir,
+            "400 Bad Request",
+            path,
+            diagnostic,
+        );
+        debug!("Responded with 400 Bad Request");
+        return;
+    }
+    let body_aligned = !chunked
+        && match body_length {
+            Some(0) => true,
+            Some(length) if length <= config.body_drain_max => match discard_body(client, length) {
+                Ok(()) => true,
+                Err(e) => {
+                    debug!("Failed to drain request body: {}", e);
+                    false
+                }
+            },
+            _ => false,
+        };
 
-    // Validate and sanitize requested path
-    match sanitize_path(&base_dir, path, index_file) {
-        Some(file_path) => match fs::read(&file_path) {
-            Ok(contents) => {
-                // Use `mime_guess` to determine content type
-                let content_type = from_path(&file_path).first_or_octet_stream().to_string();
+    // Keep the connection open unless the client, config or shutdown says otherwise
+    let wants_close = header(&headers, "Connection").is_some_and(|connection| {
+        connection
+            .split(',')
+            .any(|token| token.trim().eq_ignore_ascii_case("close"))
+    });
+    client.keep_alive = config.keep_alive
+        && !wants_close
+        && client.requests < config.keep_alive_max
+        && running.load(Ordering::Relaxed)
+        && body_aligned;
+    if !ALLOWED_METHODS.split(", ").any(|allowed| allowed == method) {
+        let allow = [("Allow", ALLOWED_METHODS.to_string())];
+        let status = "405 Method Not Allowed";
+        send_error_with(client, config, base_dir, status, path, None, &allow);
+        debug!("Responded with 405 Method Not Allowed");
+        return;
+    }
+    client.head_only = method == "HEAD";
 
-                // Send response
-                send_response(&mut stream, "200 OK", Some(&contents), &content_type);
-                println!("Responded with 200 OK");
+    // Protocol upgrades (WebSocket, h2c) are not supported, answer over HTTP/1.1 as usual
+    if let Some(upgrade) = header(&headers, "Upgrade") {
+        debug!("Ignoring Upgrade request: {}", upgrade);
+    }
+
+    // Server-wide `OPTIONS *`
+    if method == "OPTIONS" && path == "*" {
+        let allow = [("Allow", ALLOWED_METHODS.to_string())];
+        send_response(client, "204 No Content", None, None, &allow);
+        debug!("Responded with 204 No Content");
+        return;
+    }
+
+    // Accept origin-form (`/path`) and absolute-form (`http://host/path`) targets
+    let path = match parse_request_target(path) {
+        Some(path) => path,
+        None => {
+            let diagnostic = Some("Malformed request target");
+            send_error(client, config, base_dir, "400 Bad Request", "", diagnostic);
+            return;
+        }
+    };
+
+    debug!(
+        "Requested path: {} (request id {})",
+        path, client.request_id
+    );
+
+    // Drop known scanner probes before touching the filesystem (nginx 444 style)
+    if config.block_probes && is_probe(path, &config.probe_patterns) {
+        stats.blocked_probes.fetch_add(1, Ordering::Relaxed);
+        debug!("Blocked probe request, closing connection");
+        return;
+    }
+
+    // Built-in health and status endpoints. They take precedence over files of the same name
+    // unless SPECIAL_ENDPOINTS_SHADOWABLE lets a real file win.
+    let is_get = method == "GET" || method == "HEAD";
+    let is_builtin = |endpoint: &str| {
+        is_get
+            && !endpoint.is_empty()
+            && path == endpoint
+            && !(config.special_endpoints_shadowable
+                && sanitize_path(base_dir, path, config).is_ok())
+    };
+    if is_builtin(&config.livez_path) {
+        send_response(client, "200 OK", Some(b"ok\n"), Some("text/plain"), &[]);
+        debug!("Responded with 200 OK");
+        return;
+    }
+    if is_builtin(&config.readyz_path) {
+        if is_ready(base_dir, running) {
+            send_response(client, "200 OK", Some(b"ready\n"), Some("text/plain"), &[]);
+            debug!("Responded with 200 OK");
+        } else {
+            send_response(
+                client,
+                "503 Service Unavailable",
+                Some(b"not ready\n"),
+                Some("text/plain"),
+                &[],
+            );
+            debug!("Responded with 503 Service Unavailable");
+        }
+        return;
+    }
+    if is_builtin(&config.status_path) {
+        let bytes_by_type = CONTENT_TYPE_BUCKETS
+            .iter()
+            .zip(stats.bytes_by_type())
+            .map(|(bucket, bytes)| format!("\"{}\":{}", bucket, bytes))
+            .collect::<Vec<_>>()
+            .join(",");
+        let body = format!(
+            "{{\"threads\":{},\"busy_workers\":{},\"queued_jobs\":{},\"requests_served\":{},\
+             \"dispatch_failures\":{},\"truncated_transfers\":{},\"blocked_probes\":{},\
+             \"bytes_by_type\":{{{}}}}}\n",
+            config.thread_count,
+            stats.busy_workers.load(Ordering::Relaxed),
+            stats.queued_jobs.load(Ordering::Relaxed),
+            stats.requests_served.load(Ordering::Relaxed),
+            stats.dispatch_failures.load(Ordering::Relaxed),
+            stats.truncated_transfers.load(Ordering::Relaxed),
+            stats.blocked_probes.load(Ordering::Relaxed),
+            bytes_by_type
+        );
+        let no_store = [("Cache-Control", "no-store".to_string())];
+        let content_type = Some("application/json");
+        send_response(
+            client,
+            "200 OK",
+            Some(body.as_bytes()),
+            content_type,
+            &no_store,
+        );
+        debug!("Responded with 200 OK");
+        return;
+    }
+
+    // Keep RESERVE_WORKERS free for health checks: once this request would dip
+    // into the reserve, shed it instead of queueing behind busy workers
+    let busy = stats.busy_workers.load(Ordering::Relaxed);
+    if config.reserve_workers > 0
+        && busy > config.thread_count.saturating_sub(config.reserve_workers)
+    {
+        let retry_after = [("Retry-After", "1".to_string())];
+        let status = "503 Service Unavailable";
+        send_error_with(client, config, base_dir, status, path, None, &retry_after);
+        debug!(
+            "Responded with 503 Service Unavailable ({} workers busy)",
+            busy
+        );
+        return;
+    }
+
+    // Hand the PROXY_PREFIX namespace to the upstream server
+    if let Some(prefix) = &config.proxy_prefix {
+        if path.starts_with(prefix.as_str()) {
+            client.route = "proxy";
+            if is_get {
+                proxy_request(client, config, base_dir, path, &headers);
+            } else {
+                let allow = [("Allow", "GET, HEAD".to_string())];
+                let status = "405 Method Not Allowed";
+                send_error_with(client, config, base_dir, status, path, None, &allow);
+                debug!("Responded with 405 Method Not Allowed");
             }
-            Err(_) => {
-                send_response(&mut stream, "500 Internal Server Error", None, "text/plain");
-                println!("Responded with 500 Internal Server Error");
+            return;
+        }
+    }
+
+    // Permanently removed content
+    if is_gone(path, &config.gone_paths) {
+        client.route = "gone";
+        match &config.gone_body {
+            Some(body) => {
+                let content_type = Some("text/html; charset=utf-8");
+                send_response(client, "410 Gone", Some(body.as_bytes()), content_type, &[]);
             }
-        },
-        None => {
-            send_response(&mut stream, "404 Not Found", None, "text/plain");
-            println!("Responded with 404 Not Found");
+            None => send_error(client, config, base_dir, "410 Gone", path, None),
         }
+        debug!("Responded with 410 Gone");
+        return;
+    }
+
+    // `/docs` -> 301 `/docs/` for directories, so relative links in their index resolve.
+    // Only a directory resolves with the slash added, and sanitize_path applies the
+    // deny-list and symlink rules, so hidden directories still look missing.
+    let slashed_directory = || {
+        matches!(
+            sanitize_path(base_dir, &format!("{}/", path), config),
+            Ok(_) | Err(PathError::Directory(_))
+        )
+    };
+    if config.directory_slash_redirect && is_get && !path.ends_with('/') && slashed_directory() {
+        client.route = "directory-slash";
+        let query = client.target.find('?').map_or("", |i| &client.target[i..]);
+        let location = [("Location", format!("{}/{}", path, query))];
+        send_response(client, "301 Moved Permanently", None, None, &location);
+        debug!("Responded with 301 Moved Permanently");
+        return;
+    }
+
+    // Canonical directory URLs: `/dir/index.html` -> 301 `/dir/` when the index exists
+    if config.index_redirect && is_get {
+        let directory = path
+            .strip_suffix(config.index_file.as_str())
+            .filter(|directory| directory.ends_with('/'));
+        if let Some(directory) = directory {
+            if sanitize_path(base_dir, path, config).is_ok() {
+                client.route = "index-redirect";
+                let query = client.target.find('?').map_or("", |i| &client.target[i..]);
+                let location = [("Location", format!("{}{}", directory, query))];
+                send_response(client, "301 Moved Permanently", None, None, &location);
+                debug!("Responded with 301 Moved Permanently");
+                return;
+            }
+        }
+    }
+
+    // Canonicalize `/page/` to `/page` when the slashless form is a file. Directories
+    // keep their slash so relative links in their index pages still resolve.
+    if config.strip_trailing_slash && path.len() > 1 && path.ends_with('/') {
+        let stripped = path.trim_end_matches('/');
+        let is_dir = decode(stripped)
+            .is_ok_and(|decoded| base_dir.join(decoded.trim_start_matches('/')).is_dir());
+        let is_file = !is_dir && sanitize_path(base_dir, stripped, config).is_ok();
+        if !stripped.is_empty() && is_file {
+            client.route = "strip-trailing-slash";
+            let query = client.target.find('?').map_or("", |i| &client.target[i..]);
+            let location = [("Location", format!("{}{}", stripped, query))];
+            send_response(client, "301 Moved Permanently", None, None, &location);
+            debug!("Responded with 301 Moved Permanently");
+            return;
+        }
+    }
+
+    // Validate and sanitize requested path
+    match sanitize_path(base_dir, path, config) {
+        // Methods that apply to this particular resource
+        Ok(_) if method == "OPTIONS" => {
+            let allow = [("Allow", RESOURCE_METHODS.to_string())];
+            send_response(client, "204 No Content", None, None, &allow);
+            debug!("Responded with 204 No Content");
+        }
+        Ok(file_path) if config.dry_serve => dry_serve(client, config, base_dir, path, &file_path),
+        Ok(file_path) => serve_file(client, config, base_dir, path, &file_path, &headers),
+        Err(PathError::TooLong) => {
+            send_error(client, config, base_dir, "414 URI Too Long", path, None);
+            debug!("Responded with 414 URI Too Long");
+        }
+        Err(PathError::Loop) => {
+            let status = config.symlink_loop_status;
+            send_error(client, config, base_dir, status, path, None);
+            debug!("Responded with {}", status);
+        }
+        Err(PathError::Directory(dir)) if config.autoindex => {
+            if method == "OPTIONS" {
+                let allow = [("Allow", RESOURCE_METHODS.to_string())];
+                send_response(client, "204 No Content", None, None, &allow);
+                debug!("Responded with 204 No Content");
+                return;
+            }
+            // List only entries that could be served: not denied, and not symlinks
+            // when FOLLOW_SYMLINKS=false would refuse them
+            let hidden = |entry: &Path| {
+                is_denied(config, entry, base_dir) || (!config.follow_symlinks && is_symlink(entry))
+            };
+            match autoindex::render(&dir, base_dir, hidden) {
+                Ok(_) if config.dry_serve => {
+                    println!("DRY-SERVE: {} -> listing of {:?}", path, dir);
+                    let content_type = Some("text/html; charset=utf-8");
+                    send_response(client, "200 OK", None, content_type, &[]);
+                    debug!("Responded with 200 OK");
+                }
+                Ok(listing) => {
+                    debug!("Generated directory listing for {:?}", dir);
+                    let content_type = Some("text/html; charset=utf-8");
+                    let listing = listing.into_bytes();
+                    serve_generated(client, config, listing, content_type, &headers, Vec::new());
+                }
+                Err(e) => {
+                    eprintln!("Failed to list directory: {}", e);
+                    let status = "500 Internal Server Error";
+                    send_error(client, config, base_dir, status, path, None);
+                    debug!("Responded with 500 Internal Server Error");
+                }
+            }
+        }
+        // A real favicon always wins; otherwise spare browsers the 404
+        Err(PathError::NotFound) if config.default_favicon && is_get && path == "/favicon.ico" => {
+            if config.dry_serve {
+                println!("DRY-SERVE: {} -> built-in favicon", path);
+                send_response(client, "200 OK", None, Some("image/x-icon"), &[]);
+                debug!("Responded with 200 OK");
+                return;
+            }
+            debug!("Serving built-in favicon");
+            let cache = vec![("Cache-Control", "public, max-age=86400".to_string())];
+            let body = DEFAULT_FAVICON.to_vec();
+            serve_generated(client, config, body, Some("image/x-icon"), &headers, cache);
+        }
+        // Single-page apps route client-side: unknown page URLs get the root index
+        Err(PathError::NotFound | PathError::Directory(_))
+            if config.spa_mode && is_get && is_spa_route(path, &headers) =>
+        {
+            client.route = "spa-fallback";
+            match sanitize_path(base_dir, "/", config) {
+                Ok(index) if config.dry_serve => dry_serve(client, config, base_dir, path, &index),
+                Ok(index) => {
+                    debug!("SPA fallback: {} -> {:?}", path, index);
+                    serve_file(client, config, base_dir, path, &index, &headers);
+                }
+                Err(_) => {
+                    send_error(client, config, base_dir, "404 Not Found", path, None);
+                    debug!("Responded with 404 Not Found");
+                }
+            }
+        }
+        Err(PathError::NotFound | PathError::Directory(_)) => {
+            send_error(client, config, base_dir, "404 Not Found", path, None);
+            debug!("Responded with 404 Not Found");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A fresh, canonical directory under the system temp dir for one test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("rusty-socket-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.canonicalize().unwrap()
+    }
+
+    // Serve one connection with handle_client and return everything it sent back
+    fn exchange(config: impl Into<Arc<Config>>, base_dir: &Path, request: &[u8]) -> Vec<u8> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let (base_dir, config) = (Arc::new(base_dir.to_path_buf()), config.into());
+        let server = thread::spawn(move || {
+            let running = Arc::new(AtomicBool::new(true));
+            handle_client(stream, None, base_dir, config, running, Arc::default())
+        });
+
+        client.write_all(request).unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        server.join().unwrap();
+        response
+    }
+
+    // Split a response into its head and body at the first blank line
+    fn split_response(response: &[u8]) -> (String, &[u8]) {
+        let end = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .expect("response has no header terminator");
+        (
+            String::from_utf8_lossy(&response[..end]).into_owned(),
+            &response[end + 4..],
+        )
+    }
+
+    #[test]
+    fn merge_slashes_collapses_runs() {
+        assert_eq!(merge_slashes("/a//b///c"), "/a/b/c");
+        assert_eq!(merge_slashes("//index.html"), "/index.html");
+        assert_eq!(merge_slashes("/a/b/"), "/a/b/");
+        assert_eq!(merge_slashes(""), "");
+    }
+
+    #[test]
+    fn sanitize_path_merges_slashes_and_blocks_traversal() {
+        let base_dir = test_dir("sanitize");
+        fs::create_dir(base_dir.join("sub")).unwrap();
+        fs::write(base_dir.join("sub/page.html"), "page").unwrap();
+        fs::write(base_dir.join("index.html"), "index").unwrap();
+        let mut config = Config::new();
+        config.merge_slashes = true;
+
+        let page = base_dir.join("sub/page.html");
+        assert!(
+            matches!(sanitize_path(&base_dir, "/sub//page.html", &config), Ok(path) if path == page)
+        );
+        assert!(
+            matches!(sanitize_path(&base_dir, "///sub///page.html", &config), Ok(path) if path == page)
+        );
+        assert!(
+            matches!(sanitize_path(&base_dir, "//", &config), Ok(path) if path == base_dir.join("index.html"))
+        );
+
+        for traversal in [
+            "/../etc/passwd",
+            "//..//..//etc/passwd",
+            "/sub//..//..//etc/passwd",
+            "/%2e%2e/%2e%2e/etc/passwd",
+        ] {
+            assert!(
+                matches!(
+                    sanitize_path(&base_dir, traversal, &config),
+                    Err(PathError::NotFound)
+                ),
+                "{}",
+                traversal
+            );
+        }
+
+        config.merge_slashes = false;
+        assert!(
+            matches!(sanitize_path(&base_dir, "/sub//page.html", &config), Ok(path) if path == page)
+        );
+        assert!(matches!(
+            sanitize_path(&base_dir, "//..//..//etc/passwd", &config),
+            Err(PathError::NotFound)
+        ));
+
+        fs::remove_dir_all(&base_dir).unwrap();
+    }
+
+    #[test]
+    fn parse_request_target_forms() {
+        assert_eq!(parse_request_target("/index.html"), Some("/index.html"));
+        assert_eq!(parse_request_target("/search?q=rust"), Some("/search"));
+        assert_eq!(parse_request_target("http://example.com/a/b"), Some("/a/b"));
+        assert_eq!(
+            parse_request_target("HTTPS://example.com:8443/a?x=1"),
+            Some("/a")
+        );
+        assert_eq!(parse_request_target("http://example.com"), Some("/"));
+        assert_eq!(parse_request_target("http://example.com?x=1"), Some("/"));
+        assert_eq!(parse_request_target("ftp://example.com/file"), None);
+        assert_eq!(parse_request_target("http:///path"), None);
+        assert_eq!(parse_request_target("*"), None);
+    }
+
+    #[test]
+    fn open_verified_rejects_symlinks_and_non_regular_files() {
+        let base_dir = test_dir("open-verified");
+        fs::write(base_dir.join("real.txt"), "real").unwrap();
+        std::os::unix::fs::symlink(base_dir.join("real.txt"), base_dir.join("link.txt")).unwrap();
+        fs::create_dir(base_dir.join("dir")).unwrap();
+
+        let (_, metadata) = open_verified(&base_dir.join("real.txt"), &base_dir).unwrap();
+        assert_eq!(metadata.len(), 4);
+
+        // O_NOFOLLOW turns a symlinked final component into ELOOP, reported as missing
+        let err = open_verified(&base_dir.join("link.txt"), &base_dir).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(err.to_string(), "symlink swapped in");
+
+        let err = open_verified(&base_dir.join("dir"), &base_dir).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(err.to_string(), "not a regular file");
+
+        let err = open_verified(Path::new("/dev/null"), Path::new("/dev")).unwrap_err();
+        assert_eq!(err.to_string(), "not a regular file");
+
+        fs::remove_dir_all(&base_dir).unwrap();
+    }
+
+    #[test]
+    fn large_body_is_delivered_in_full() {
+        let base_dir = test_dir("large-body");
+        // Several times any default socket send buffer, so writes must block and resume
+        let body: Vec<u8> = (0..8 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        fs::write(base_dir.join("large.bin"), &body).unwrap();
+
+        let response = exchange(
+            Config::new(),
+            &base_dir,
+            b"GET /large.bin HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        );
+        let (head, received) = split_response(&response);
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert!(
+            head.contains(&format!("Content-Length: {}", body.len())),
+            "{}",
+            head
+        );
+        assert!(
+            received == body.as_slice(),
+            "body differs ({} bytes)",
+            received.len()
+        );
+
+        fs::remove_dir_all(&base_dir).unwrap();
+    }
+
+    #[test]
+    fn websocket_upgrade_gets_a_normal_response() {
+        let base_dir = test_dir("upgrade");
+        fs::write(base_dir.join("index.html"), "hello").unwrap();
+
+        let response = exchange(
+            Config::new(),
+            &base_dir,
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, close\r\n\
+              Upgrade: websocket\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              Sec-WebSocket-Version: 13\r\n\r\n",
+        );
+        let (head, body) = split_response(&response);
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert!(!head.contains("Sec-WebSocket-Accept"), "{}", head);
+        assert!(!head.to_ascii_lowercase().contains("upgrade"), "{}", head);
+        assert_eq!(body, b"hello");
+
+        fs::remove_dir_all(&base_dir).unwrap();
+    }
+
+    #[test]
+    fn get_body_is_drained_before_the_next_request() {
+        let base_dir = test_dir("get-body");
+        fs::write(base_dir.join("a.txt"), "first").unwrap();
+        fs::write(base_dir.join("b.txt"), "second").unwrap();
+
+        // The body must not be parsed as the start of the second request
+        let response = exchange(
+            Config::new(),
+            &base_dir,
+            b"GET /a.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 11\r\n\r\nGET /x.txt \
+              GET /b.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        );
+        let (head, rest) = split_response(&response);
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert!(rest.starts_with(b"first"));
+
+        let (head, body) = split_response(&rest[5..]);
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert_eq!(body, b"second");
+
+        fs::remove_dir_all(&base_dir).unwrap();
+    }
+
+    #[test]
+    fn glob_match_wildcards() {
+        assert!(glob_match("*.bak", "index.html.bak"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("file?.txt", "file1.txt"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        assert!(glob_match("private/*", "private/keys.pem"));
+        assert!(!glob_match("*.bak", "index.html"));
+        assert!(!glob_match("file?.txt", "file10.txt"));
+        assert!(!glob_match("a*b*c", "axxbyy"));
+    }
+
+    #[test]
+    fn is_denied_dotfiles_and_patterns() {
+        let base_dir = Path::new("/srv/www");
+        let mut config = Config::new();
+        config.allow_dotfiles = false;
+        config.deny_patterns = vec!["*.bak".to_string(), "/private/*".to_string()];
+
+        let denied = |path: &str| is_denied(&config, &base_dir.join(path), base_dir);
+        assert!(denied(".env"));
+        assert!(denied("sub/.git/config"));
+        assert!(denied("old/index.html.bak"));
+        assert!(denied("private/keys.pem"));
+        assert!(!denied(".well-known/security.txt"));
+        assert!(!denied("index.html"));
+        assert!(!denied("sub/private/keys.pem"));
+        assert!(!is_denied(&config, Path::new("/elsewhere/.env"), base_dir));
+
+        config.allow_dotfiles = true;
+        assert!(!is_denied(&config, &base_dir.join(".env"), base_dir));
+    }
+
+    #[test]
+    fn dotfiles_are_hidden_while_normal_files_serve() {
+        let base_dir = test_dir("dotfiles");
+        fs::write(base_dir.join(".env"), "SECRET=1").unwrap();
+        fs::create_dir_all(base_dir.join("sub/.git")).unwrap();
+        fs::write(base_dir.join("sub/.git/config"), "[core]").unwrap();
+        fs::write(base_dir.join("sub/page.html"), "page").unwrap();
+        let mut config = Config::new();
+        config.allow_dotfiles = false;
+
+        assert!(matches!(
+            sanitize_path(&base_dir, "/.env", &config),
+            Err(PathError::NotFound)
+        ));
+        assert!(matches!(
+            sanitize_path(&base_dir, "/sub/.git/config", &config),
+            Err(PathError::NotFound)
+        ));
+        assert!(sanitize_path(&base_dir, "/sub/page.html", &config).is_ok());
+
+        let config = Arc::new(config);
+        for (path, status) in [
+            ("/.env", "404"),
+            ("/sub/.git/config", "404"),
+            ("/sub/page.html", "200"),
+        ] {
+            let request = format!(
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                path
+            );
+            let response = exchange(config.clone(), &base_dir, request.as_bytes());
+            let (head, body) = split_response(&response);
+            assert!(
+                head.starts_with(&format!("HTTP/1.1 {}", status)),
+                "{}: {}",
+                path,
+                head
+            );
+            assert!(!body.starts_with(b"SECRET") && !body.starts_with(b"[core]"));
+        }
+
+        fs::remove_dir_all(&base_dir).unwrap();
+    }
+
+    #[test]
+    fn symlinks_follow_the_follow_symlinks_setting() {
+        let root = test_dir("symlinks");
+        let (base_dir, outside) = (root.join("www"), root.join("outside"));
+        fs::create_dir_all(&base_dir).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(base_dir.join("real.txt"), "real").unwrap();
+        fs::write(outside.join("secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink(base_dir.join("real.txt"), base_dir.join("in-tree.txt"))
+            .unwrap();
+        std::os::unix::fs::symlink(outside.join("secret.txt"), base_dir.join("out-of-tree.txt"))
+            .unwrap();
+        let mut config = Config::new();
+
+        // In-tree links resolve to their target; out-of-tree links never serve
+        config.follow_symlinks = true;
+        assert!(matches!(
+            sanitize_path(&base_dir, "/in-tree.txt", &config),
+            Ok(path) if path == base_dir.join("real.txt")
+        ));
+        assert!(matches!(
+            sanitize_path(&base_dir, "/out-of-tree.txt", &config),
+            Err(PathError::NotFound)
+        ));
+
+        config.follow_symlinks = false;
+        for path in ["/in-tree.txt", "/out-of-tree.txt"] {
+            assert!(matches!(
+                sanitize_path(&base_dir, path, &config),
+                Err(PathError::NotFound)
+            ));
+        }
+        assert!(sanitize_path(&base_dir, "/real.txt", &config).is_ok());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn autoindex_hides_symlinks_unless_followed() {
+        let base_dir = test_dir("autoindex-symlinks");
+        fs::write(base_dir.join("real.txt"), "real").unwrap();
+        std::os::unix::fs::symlink(base_dir.join("real.txt"), base_dir.join("linked.txt")).unwrap();
+
+        for (follow_symlinks, listed) in [(true, true), (false, false)] {
+            let mut config = Config::new();
+            config.autoindex = true;
+            config.follow_symlinks = follow_symlinks;
+            let response = exchange(
+                config,
+                &base_dir,
+                b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            );
+            let (head, body) = split_response(&response);
+            let body = String::from_utf8_lossy(body);
+            assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+            assert!(body.contains("real.txt"), "{}", body);
+            assert_eq!(body.contains("linked.txt"), listed, "{}", body);
+        }
+
+        fs::remove_dir_all(&base_dir).unwrap();
+    }
+
+    #[test]
+    fn absolute_form_target_serves_the_file() {
+        let base_dir = test_dir("absolute-form");
+        fs::create_dir(base_dir.join("sub")).unwrap();
+        fs::write(base_dir.join("sub/page.html"), "page").unwrap();
+
+        let response = exchange(
+            Config::new(),
+            &base_dir,
+            b"GET http://example.com/sub/page.html?x=1 HTTP/1.1\r\nHost: example.com\r\n\
+              Connection: close\r\n\r\n",
+        );
+        let (head, body) = split_response(&response);
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert_eq!(body, b"page");
+
+        fs::remove_dir_all(&base_dir).unwrap();
     }
 }
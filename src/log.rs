@@ -0,0 +1,209 @@
+use std::{
+    net::IpAddr,
+    str::FromStr,
+    sync::atomic::{AtomicU8, Ordering},
+    time::{Duration, SystemTime},
+};
+
+// Verbosity from LOG_LEVEL: errors only, plus access logs, plus per-request detail
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Error = 0,
+    Info = 1,
+    Debug = 2,
+}
+
+impl FromStr for Level {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, ()> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            _ => Err(()),
+        }
+    }
+}
+
+// Access log line format from LOG_FORMAT
+#[derive(Clone, Copy)]
+pub enum Format {
+    Common,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, ()> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "clf" | "common" => Ok(Format::Common),
+            "json" => Ok(Format::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+// Process-wide level, set once at startup so workers can check it without a Config
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+// Per-request detail on stdout, only at LOG_LEVEL=debug
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Debug) {
+            println!($($arg)*);
+        }
+    };
+}
+pub(crate) use debug;
+
+// One completed request, as written to the access log
+pub struct Access<'a> {
+    pub client: Option<IpAddr>,
+    pub method: &'a str,
+    pub path: &'a str,
+    pub version: &'a str,
+    pub status: u16,
+    pub bytes_sent: u64,
+    pub duration: Duration,
+    pub request_id: &'a str,
+    pub compression: Option<(u64, u64)>, // Original and compressed body size
+    pub route: Option<&'a str>,          // Matched routing rule, when LOG_ROUTE is on
+    pub keep_alive: bool,                // Connection left open for another request
+}
+
+// Write an access log line to stdout (errors go to stderr, so collectors can split them)
+pub fn access(format: Format, entry: &Access) {
+    if !enabled(Level::Info) {
+        return;
+    }
+
+    let client = entry
+        .client
+        .map_or_else(|| "-".to_string(), |ip| ip.to_string());
+    let [day, month, year, time] = timestamp_parts(SystemTime::now());
+    let millis = entry.duration.as_secs_f64() * 1000.0;
+
+    // Compressed size over original, only when a body was actually compressed
+    let (encoding, ratio) = match entry.compression {
+        Some((original, compressed)) if original > 0 => {
+            ("gzip", Some(compressed as f64 / original as f64))
+        }
+        _ => ("identity", None),
+    };
+
+    let connection = if entry.keep_alive {
+        "keep-alive"
+    } else {
+        "close"
+    };
+
+    match format {
+        // Common Log Format plus response time, request id, encoding, ratio, connection
+        // and rule
+        Format::Common => println!(
+            "{} - - [{}/{}/{}:{} +0000] \"{} {} {}\" {} {} {:.3}ms {} {} {} {}{}",
+            client,
+            day,
+            month,
+            year,
+            time,
+            clf_escape(entry.method),
+            clf_escape(entry.path),
+            clf_escape(entry.version),
+            entry.status,
+            entry.bytes_sent,
+            millis,
+            if entry.request_id.is_empty() {
+                "-"
+            } else {
+                entry.request_id
+            },
+            encoding,
+            ratio.map_or_else(|| "-".to_string(), |ratio| format!("{:.3}", ratio)),
+            connection,
+            entry
+                .route
+                .map_or_else(String::new, |route| format!(" {}", route))
+        ),
+        Format::Json => println!(
+            "{{\"time\":\"{}-{:02}-{}T{}Z\",\"client\":\"{}\",\"method\":\"{}\",\"path\":\"{}\",\
+             \"protocol\":\"{}\",\"status\":{},\"bytes\":{},\"duration_ms\":{:.3},\
+             \"request_id\":\"{}\",\"encoding\":\"{}\",\"compression_ratio\":{},\"keep_alive\":{}{}}}",
+            year,
+            month_number(&month),
+            day,
+            time,
+            client,
+            json_escape(entry.method),
+            json_escape(entry.path),
+            json_escape(entry.version),
+            entry.status,
+            entry.bytes_sent,
+            millis,
+            json_escape(entry.request_id),
+            encoding,
+            ratio.map_or_else(|| "null".to_string(), |ratio| format!("{:.3}", ratio)),
+            entry.keep_alive,
+            entry.route.map_or_else(String::new, |route| format!(
+                ",\"route\":\"{}\"",
+                json_escape(route)
+            ))
+        ),
+    }
+}
+
+// Day, month name, year and HH:MM:SS in UTC, taken from the IMF-fixdate form
+// ("Thu, 15 Oct 2026 08:46:39 GMT")
+fn timestamp_parts(now: SystemTime) -> [String; 4] {
+    let date = httpdate::fmt_http_date(now);
+    let mut parts = date.split_whitespace().skip(1).map(str::to_string);
+    std::array::from_fn(|_| parts.next().unwrap_or_default())
+}
+
+fn month_number(month: &str) -> usize {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    MONTHS
+        .iter()
+        .position(|&name| name == month)
+        .map_or(0, |i| i + 1)
+}
+
+// Escape quotes, backslashes and control bytes the way Apache does for CLF fields
+fn clf_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Escape a value for use inside a JSON string
+fn json_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -1,23 +1,225 @@
+mod autoindex;
 mod handler;
+mod log;
+mod mime;
+mod ratelimit;
+mod sandbox;
 mod structs;
+mod template;
+mod tls;
 
-use crate::handler::handle_client;
-use crate::structs::{Config, ThreadPool};
+use crate::handler::{handle_client, reject_connection, QueuedConnection};
+use crate::ratelimit::RateLimiter;
+use crate::structs::{
+    join_with_deadline, Config, ExecuteError, Stats, ThreadPool, CONTENT_TYPE_BUCKETS,
+};
 use signal_hook::iterator::Signals;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
 use std::thread;
 use std::time::Duration;
 use std::{
     io,
-    net::TcpListener,
-    path::Path,
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
     sync::atomic::{AtomicBool, Ordering},
     sync::Arc,
 };
 
+// Backoff after a failed poll so a persistent error does not spin
+const POLL_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+// Self-pipe that turns readable once shutdown starts, letting accept loops block in
+// poll without a timeout. It is never drained, so every loop polling it wakes up.
+struct ShutdownPipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl ShutdownPipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // pipe2 succeeded, so both descriptors are ours to close
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        Ok(ShutdownPipe { read, write })
+    }
+
+    // Wake every accept loop; a full pipe is already readable, so errors are ignored
+    fn notify(&self) {
+        unsafe { libc::write(self.write.as_raw_fd(), [1u8].as_ptr().cast(), 1) };
+    }
+}
+
+// Block until any listener has a pending connection or the shutdown pipe is readable
+fn wait_readable(listeners: &[TcpListener], shutdown: &ShutdownPipe) -> io::Result<()> {
+    let mut fds: Vec<libc::pollfd> = listeners
+        .iter()
+        .map(AsRawFd::as_raw_fd)
+        .chain([shutdown.read.as_raw_fd()])
+        .map(|fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+
+    match unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } {
+        -1 => {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                Ok(()) // Signal received, let the caller recheck the shutdown flag
+            } else {
+                Err(e)
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+// Bind a nonblocking listener, optionally with SO_REUSEPORT so several sockets can share
+// the same address
+fn bind_listener(addr: SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+    let listener = if reuse_port {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_reuse_port(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        TcpListener::from(socket)
+    } else {
+        TcpListener::bind(addr)?
+    };
+    listener.set_nonblocking(true)?; // Prevent blocking on slow clients
+    Ok(listener)
+}
+
+// Bind every configured address. A failed address either aborts startup or, with
+// BIND_FAILURE=continue, is logged and skipped as long as one address succeeds.
+fn bind_listeners(config: &Config) -> io::Result<Vec<TcpListener>> {
+    let mut listeners = Vec::new();
+    for address in config
+        .addresses
+        .iter()
+        .filter_map(|address| address.as_ref().ok())
+    {
+        match bind_listener(*address, config.reuseport_sharding) {
+            Ok(listener) => listeners.push(listener),
+            Err(e) if config.bind_failure_continue => {
+                eprintln!("Failed to bind {}: {}, continuing", address, e);
+            }
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("Failed to bind {}: {}", address, e),
+                ))
+            }
+        }
+    }
+
+    if listeners.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "No address could be bound",
+        ));
+    }
+    Ok(listeners)
+}
+
+// Accept connections on all listeners until shutdown, handing each one to `dispatch`
+fn accept_loop(
+    listeners: &[TcpListener],
+    running: &AtomicBool,
+    shutdown: &ShutdownPipe,
+    mut dispatch: impl FnMut(TcpStream),
+) {
+    while running.load(Ordering::Relaxed) {
+        let mut accepted = false;
+        for listener in listeners {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    accepted = true;
+                    dispatch(stream);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => eprintln!("Connection failed: {}", e),
+            }
+        }
+
+        // Block until a connection arrives or shutdown starts
+        if !accepted {
+            if let Err(e) = wait_readable(listeners, shutdown) {
+                eprintln!("Failed to poll listeners: {}", e);
+                thread::sleep(POLL_ERROR_BACKOFF); // Prevent busy loop
+            }
+        }
+    }
+}
+
+// Answer clients over RATE_LIMIT_RPS with 429, handing back the streams that may proceed
+fn admit(
+    limiter: Option<&RateLimiter>,
+    stream: TcpStream,
+    tls: bool,
+    stats: &Stats,
+) -> Option<TcpStream> {
+    let (Some(limiter), Ok(peer)) = (limiter, stream.peer_addr()) else {
+        return Some(stream);
+    };
+    match limiter.acquire(peer.ip()) {
+        Ok(()) => Some(stream),
+        Err(retry_after) => {
+            let status = "429 Too Many Requests";
+            reject_connection(stream, tls, status, retry_after, stats);
+            None
+        }
+    }
+}
+
+// Log connections, requests, bytes, errors, aborted or truncated transfers and blocked
+// probes seen in each interval
+fn log_summary(stats: &Stats, interval: Duration) {
+    let mut previous = stats.snapshot();
+    let mut previous_by_type = stats.bytes_by_type();
+    loop {
+        thread::sleep(interval);
+        let current = stats.snapshot();
+        let current_by_type = stats.bytes_by_type();
+        let [accepted, requests, bytes, errors, aborted, aborted_bytes, truncated, probes] =
+            std::array::from_fn(|i| current[i] - previous[i]);
+        let by_type: Vec<String> = CONTENT_TYPE_BUCKETS
+            .iter()
+            .enumerate()
+            .map(|(i, bucket)| (bucket, current_by_type[i] - previous_by_type[i]))
+            .filter(|&(_, bytes)| bytes > 0)
+            .map(|(bucket, bytes)| format!("{}={}", bucket, bytes))
+            .collect();
+        println!(
+            "Summary (last {:?}): {} connections, {} requests, {} bytes sent [{}], {} errors, \
+             {} aborted transfers ({} bytes), {} truncated transfers, {} blocked probes",
+            interval,
+            accepted,
+            requests,
+            bytes,
+            by_type.join(" "),
+            errors,
+            aborted,
+            aborted_bytes,
+            truncated,
+            probes
+        );
+        previous = current;
+        previous_by_type = current_by_type;
+    }
+}
+
 fn main() -> io::Result<()> {
     // Load configuration
     let config = Config::new();
-    let base_dir = Path::new(&config.base_dir).canonicalize()?;
+    log::set_level(config.log_level);
+    let mut base_dir = Path::new(&config.base_dir).canonicalize()?;
     if !base_dir.is_dir() {
         return Err(io::Error::new(
             io::ErrorKind::NotFound,
@@ -25,51 +227,246 @@ fn main() -> io::Result<()> {
         ));
     }
 
-    // Create TCP listener with explicit binding
-    let listener = TcpListener::bind(&config.address)?;
-    listener.set_nonblocking(true)?; // Prevent blocking on slow clients
+    // Addresses that do not parse or resolve fail startup just like failed binds
+    for e in config
+        .addresses
+        .iter()
+        .filter_map(|address| address.as_ref().err())
+    {
+        if !config.bind_failure_continue {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid address {}", e),
+            ));
+        }
+        eprintln!("Ignoring address {}, continuing", e);
+    }
+
+    // Create TCP listeners with explicit binding: a set per worker when sharding with
+    // SO_REUSEPORT (the kernel balances connections), otherwise one shared set
+    let listener_sets = if config.reuseport_sharding {
+        config.thread_count
+    } else {
+        1
+    };
+    let mut listeners = (0..listener_sets)
+        .map(|_| bind_listeners(&config))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    // Load TLS material while the files outside base_dir are still reachable
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::load_config(cert, key)?),
+        (None, None) => None,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TLS_CERT and TLS_KEY must be set together",
+            ))
+        }
+    };
+
+    if config.proxy_prefix.is_some() != config.proxy_upstream.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "PROXY_PREFIX and PROXY_UPSTREAM must both be set and valid",
+        ));
+    }
+
+    // Jail into the base directory and drop root privileges now that the socket is bound.
+    // Anything read from outside base_dir must be loaded before this point.
+    let run_gid = sandbox::resolve_gid(config.run_uid, config.run_gid);
+    if config.chroot && sandbox::enter_chroot(&base_dir)? {
+        base_dir = PathBuf::from("/");
+    }
+    sandbox::drop_privileges(config.run_uid, run_gid, &base_dir)?;
 
     // Print configuration
     println!("rusty-socket v0.1.1");
-    println!("Opening a rusty-socket @: {}", config.address);
+    let bound: Vec<String> = listeners[0]
+        .iter()
+        .filter_map(|listener| listener.local_addr().ok())
+        .map(|addr| addr.to_string())
+        .collect();
+    println!("Opening a rusty-socket @: {}", bound.join(", "));
+    if tls.is_some() {
+        println!("TLS enabled (HTTPS only)");
+    }
+    if let (Some(prefix), Some((upstream, addr))) = (&config.proxy_prefix, &config.proxy_upstream) {
+        println!("Proxying {}* to {} ({})", prefix, upstream, addr);
+    }
+    if let Some((rate, burst)) = config.rate_limit {
+        println!("Rate limit per client: {}/s, burst {}", rate, burst);
+    }
+    if !config.status_path.is_empty() {
+        println!("Status endpoint: {}", config.status_path);
+    }
     println!("Base directory: {:?}", &base_dir);
     println!("Index file: {}", config.index_file);
     println!("Thread count: {}", config.thread_count);
+    if !config.reuseport_sharding {
+        println!("Queue capacity: {}", config.queue_capacity);
+    }
+    if let Some(stack_size) = config.worker_stack_size {
+        println!("Worker stack size: {} bytes", stack_size);
+    }
+    if let Some(timeout) = config.drain_timeout {
+        println!("Shutdown drain limit per connection: {:?}", timeout);
+    }
+    if let Some(max_bytes) = config.drain_max_bytes {
+        println!("Shutdown drain limit per connection: {} bytes", max_bytes);
+    }
+    if config.dry_serve {
+        println!("DRY-SERVE: requests are resolved and logged, no file contents are sent");
+    }
+    if let Some((min, max)) = config.response_jitter {
+        println!("TESTING: response jitter enabled ({:?} - {:?})", min, max);
+    }
 
-    // Create a thread pool
-    let pool = ThreadPool::new(config.thread_count);
+    if config.reuseport_sharding {
+        println!("REUSEPORT sharding: each worker accepts on its own socket");
+    }
 
     // Wrap shared data in Arc
     let base_dir = Arc::new(base_dir);
-    let index_file = Arc::new(config.index_file);
+    let config = Arc::new(config);
+    let stats = Arc::new(Stats::default());
+    let limiter = config
+        .rate_limit
+        .map(|(rate, burst)| Arc::new(RateLimiter::new(rate, burst)));
 
     // Graceful shutdown flag
     let running = Arc::new(AtomicBool::new(true));
+    let shutdown = Arc::new(ShutdownPipe::new()?);
 
-    // Handle SIGTERM for graceful shutdown
-    let mut signals = Signals::new(&[signal_hook::consts::SIGTERM])?;
+    // Handle SIGTERM and SIGINT for graceful shutdown; a second signal exits immediately
+    let mut signals = Signals::new([signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT])?;
     let shutdown_flag = running.clone();
+    let shutdown_pipe = shutdown.clone();
     thread::spawn(move || {
-        for _ in signals.forever() {
-            println!("\nReceived SIGTERM. Shutting down...");
+        let mut pending = signals.forever();
+        if let Some(signal) = pending.next() {
+            let name = if signal == signal_hook::consts::SIGINT {
+                "SIGINT"
+            } else {
+                "SIGTERM"
+            };
+            println!("\nReceived {}. Shutting down...", name);
             shutdown_flag.store(false, Ordering::Relaxed);
-            break;
+            shutdown_pipe.notify();
+        }
+        if pending.next().is_some() {
+            eprintln!("Received second signal, exiting immediately");
+            std::process::exit(1);
         }
     });
 
-    // Handle incoming connections
-    while running.load(Ordering::Relaxed) {
-        match listener.accept() {
-            Ok((stream, _)) => {
+    // Periodically log a summary of activity
+    if let Some(interval) = config.summary_interval {
+        let stats = stats.clone();
+        thread::spawn(move || log_summary(&stats, interval));
+    }
+
+    if config.reuseport_sharding {
+        // Each shard accepts and serves its own connections, no shared queue
+        let shards: Vec<_> = listeners
+            .into_iter()
+            .enumerate()
+            .map(|(id, listeners)| {
+                let tls = tls.clone();
                 let base_dir = base_dir.clone();
-                let index_file = index_file.clone();
-                pool.execute(move || handle_client(stream, base_dir, &index_file));
+                let config = config.clone();
+                let running = running.clone();
+                let stats = stats.clone();
+                let shutdown = shutdown.clone();
+                let limiter = limiter.clone();
+                let mut builder = thread::Builder::new().name(format!("shard-{}", id));
+                if let Some(stack_size) = config.worker_stack_size {
+                    builder = builder.stack_size(stack_size);
+                }
+                builder
+                    .spawn(move || {
+                        accept_loop(&listeners, &running, &shutdown, |stream| {
+                            stats.connections_accepted.fetch_add(1, Ordering::Relaxed);
+                            let limiter = limiter.as_deref();
+                            let Some(stream) = admit(limiter, stream, tls.is_some(), &stats) else {
+                                return;
+                            };
+                            handle_client(
+                                stream,
+                                tls.clone(),
+                                base_dir.clone(),
+                                config.clone(),
+                                running.clone(),
+                                stats.clone(),
+                            );
+                        })
+                    })
+                    .expect("Failed to spawn shard thread")
+            })
+            .collect();
+
+        // Shards stop accepting on their own; give in-flight requests a bounded drain
+        let busy = join_with_deadline(shards, config.shutdown_timeout);
+        if busy > 0 {
+            eprintln!(
+                "Shutdown timeout elapsed with {} shards still serving, exiting",
+                busy
+            );
+        }
+    } else {
+        // Create a thread pool
+        let mut pool = ThreadPool::new(
+            config.thread_count,
+            config.queue_capacity,
+            stats.queued_jobs.clone(),
+            config.worker_stack_size,
+        );
+
+        // Handle incoming connections
+        let listeners = listeners.remove(0);
+        accept_loop(&listeners, &running, &shutdown, |stream| {
+            stats.connections_accepted.fetch_add(1, Ordering::Relaxed);
+            let limiter = limiter.as_deref();
+            let Some(stream) = admit(limiter, stream, tls.is_some(), &stats) else {
+                return;
+            };
+
+            // Shed load rather than let the queue grow behind busy workers
+            if pool.would_block() {
+                let status = "503 Service Unavailable";
+                let retry_after = Duration::from_secs(1);
+                reject_connection(stream, tls.is_some(), status, retry_after, &stats);
+                return;
             }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                thread::sleep(Duration::from_millis(100)); // Prevent busy loop
-                continue;
+            let connection = QueuedConnection::new(stream, tls.is_some(), stats.clone());
+            let tls = tls.clone();
+            let base_dir = base_dir.clone();
+            let config = config.clone();
+            let running = running.clone();
+            let job_stats = stats.clone();
+            let job = move || {
+                if let Some(stream) = connection.take() {
+                    handle_client(stream, tls, base_dir, config, running, job_stats);
+                }
+            };
+
+            // A job that could not be queued is dropped, answering its client with 503
+            if let Err(e) = pool.execute(job) {
+                stats.dispatch_failures.fetch_add(1, Ordering::Relaxed);
+                match e {
+                    ExecuteError::Full => eprintln!("Worker queue full, rejected connection"),
+                    ExecuteError::Closed => eprintln!("Thread pool closed, rejected connection"),
+                }
             }
-            Err(e) => eprintln!("Connection failed: {}", e),
+        });
+
+        // Let queued and in-flight requests finish, but not forever
+        let busy = pool.shutdown_timeout(config.shutdown_timeout);
+        if busy > 0 {
+            eprintln!(
+                "Shutdown timeout elapsed with {} workers still busy, exiting",
+                busy
+            );
         }
     }
 
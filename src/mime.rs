@@ -0,0 +1,229 @@
+use mime_guess::from_path;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+// Well-known extensionless files that are plain text; CHARSET is added when served
+const DEFAULT_FILENAME_TYPES: &[(&str, &str)] = &[
+    ("AUTHORS", "text/plain"),
+    ("CHANGELOG", "text/plain"),
+    ("CONTRIBUTING", "text/plain"),
+    ("COPYING", "text/plain"),
+    ("Dockerfile", "text/plain"),
+    ("LICENSE", "text/plain"),
+    ("Makefile", "text/plain"),
+    ("NOTICE", "text/plain"),
+    ("README", "text/plain"),
+];
+
+// Extensions the built-in table gets wrong; a loaded mime.types file takes precedence
+const DEFAULT_EXTENSION_TYPES: &[(&str, &str)] = &[
+    // Source maps (app.css.map, app.js.map) are JSON, not plain text
+    ("map", "application/json"),
+];
+
+// Content type lookup: exact filenames first, then extension overrides, then
+// extensions from a loaded mime.types file, then the built-in `mime_guess` table. Modifier suffixes
+// (locales like `.en`) are skipped so the extension before them decides.
+pub struct MimeTypes {
+    filenames: HashMap<String, String>,
+    case_insensitive: bool,
+    extensions: HashMap<String, String>,
+    modifiers: HashSet<String>,
+}
+
+impl MimeTypes {
+    // Build the table from the defaults plus `name=type` and `extension=type` entries
+    pub fn new(
+        filename_types: &[String],
+        case_insensitive: bool,
+        extension_types: &[String],
+        mime_types_file: Option<&str>,
+        modifier_suffixes: &[String],
+    ) -> Self {
+        let key = |name: &str| {
+            if case_insensitive {
+                name.to_ascii_lowercase()
+            } else {
+                name.to_string()
+            }
+        };
+
+        let mut filenames: HashMap<String, String> = DEFAULT_FILENAME_TYPES
+            .iter()
+            .map(|(name, content_type)| (key(name), content_type.to_string()))
+            .collect();
+
+        for entry in filename_types {
+            match entry.split_once('=') {
+                Some((name, content_type)) if !name.trim().is_empty() => {
+                    filenames.insert(key(name.trim()), content_type.trim().to_string());
+                }
+                _ => eprintln!("Ignoring malformed filename type: {}", entry),
+            }
+        }
+
+        // Extensions from a system mime.types file, if one is configured and valid
+        let mut extensions: HashMap<String, String> = DEFAULT_EXTENSION_TYPES
+            .iter()
+            .map(|(extension, content_type)| (extension.to_string(), content_type.to_string()))
+            .collect();
+        extensions.extend(
+            match mime_types_file.map(|path| (path, fs::read_to_string(path))) {
+                None => HashMap::new(),
+                Some((path, Ok(contents))) => match parse_mime_types(&contents) {
+                    Some(extensions) => {
+                        println!("Loaded {} MIME extensions from {}", extensions.len(), path);
+                        extensions
+                    }
+                    None => {
+                        eprintln!(
+                            "Warning: malformed MIME types file {}, using built-in table",
+                            path
+                        );
+                        HashMap::new()
+                    }
+                },
+                Some((path, Err(e))) => {
+                    eprintln!(
+                        "Warning: cannot read MIME types file {}: {}, using built-in table",
+                        path, e
+                    );
+                    HashMap::new()
+                }
+            },
+        );
+
+        // Explicit overrides beat both the defaults and the mime.types file
+        for entry in extension_types {
+            match entry.split_once('=') {
+                Some((extension, content_type))
+                    if !extension.trim().trim_start_matches('.').is_empty()
+                        && content_type.contains('/') =>
+                {
+                    let extension = extension.trim().trim_start_matches('.');
+                    extensions.insert(
+                        extension.to_ascii_lowercase(),
+                        content_type.trim().to_string(),
+                    );
+                }
+                _ => eprintln!("Ignoring malformed MIME override: {}", entry),
+            }
+        }
+
+        let modifiers = modifier_suffixes
+            .iter()
+            .map(|suffix| suffix.trim_start_matches('.').to_ascii_lowercase())
+            .collect();
+
+        Self {
+            filenames,
+            case_insensitive,
+            extensions,
+            modifiers,
+        }
+    }
+
+    // Resolve the content type for a file, or None if it is unknown
+    pub fn content_type(&self, path: &Path) -> Option<String> {
+        if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+            let found = if self.case_insensitive {
+                self.filenames.get(&name.to_ascii_lowercase())
+            } else {
+                self.filenames.get(name)
+            };
+            if let Some(content_type) = found {
+                return Some(content_type.clone());
+            }
+        }
+
+        let name = path.file_name().and_then(|name| name.to_str());
+        let path = name.map_or(path, |name| Path::new(self.strip_modifiers(name)));
+        if let Some(extension) = path.extension().and_then(|ext| ext.to_str()) {
+            if let Some(content_type) = self.extensions.get(&extension.to_ascii_lowercase()) {
+                return Some(content_type.clone());
+            }
+        }
+
+        from_path(path).first().map(|mime| mime.to_string())
+    }
+
+    // Drop trailing modifier suffixes from a file name, keeping at least one
+    // extension: index.html.en -> index.html, but a bare notes.en stays as it is
+    fn strip_modifiers<'a>(&self, mut name: &'a str) -> &'a str {
+        while let Some((stem, suffix)) = name.rsplit_once('.') {
+            if !stem.contains('.') || !self.modifiers.contains(&suffix.to_ascii_lowercase()) {
+                break;
+            }
+            name = stem;
+        }
+        name
+    }
+}
+
+// Parse an Apache (`type ext ext`) or nginx (`types { type ext ext; }`) mime.types file.
+// Returns None if any entry is malformed.
+fn parse_mime_types(contents: &str) -> Option<HashMap<String, String>> {
+    let mut extensions = HashMap::new();
+
+    let statements = contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or(""))
+        .flat_map(|line| line.split(';'))
+        .map(|statement| statement.replace(['{', '}'], " "));
+
+    for statement in statements {
+        // Skip the nginx `types` block keyword
+        let mut tokens = statement
+            .split_whitespace()
+            .skip_while(|&token| token == "types");
+        let Some(content_type) = tokens.next() else {
+            continue; // Blank line or comment
+        };
+        if !content_type.contains('/') {
+            return None;
+        }
+        for extension in tokens {
+            extensions.insert(extension.to_ascii_lowercase(), content_type.to_string());
+        }
+    }
+
+    Some(extensions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mime_types() -> MimeTypes {
+        MimeTypes::new(&[], false, &[], None, &["en".to_string(), "fr".to_string()])
+    }
+
+    #[test]
+    fn strip_modifiers_keeps_one_extension() {
+        let types = mime_types();
+        assert_eq!(types.strip_modifiers("index.html.en"), "index.html");
+        assert_eq!(types.strip_modifiers("index.html.FR.en"), "index.html");
+        assert_eq!(types.strip_modifiers("archive.tar.gz"), "archive.tar.gz");
+        assert_eq!(types.strip_modifiers("app.css.map"), "app.css.map");
+        assert_eq!(types.strip_modifiers("notes.en"), "notes.en");
+    }
+
+    #[test]
+    fn content_type_uses_the_last_real_extension() {
+        let types = mime_types();
+        let content_type = |name: &str| types.content_type(Path::new(name));
+        assert_eq!(content_type("index.html.en").as_deref(), Some("text/html"));
+        assert_eq!(
+            content_type("archive.tar.gz").as_deref(),
+            Some("application/gzip")
+        );
+        assert_eq!(
+            content_type("app.css.map").as_deref(),
+            Some("application/json")
+        );
+        assert_eq!(content_type("LICENSE").as_deref(), Some("text/plain"));
+    }
+}
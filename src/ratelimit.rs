@@ -0,0 +1,79 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// How often idle buckets are swept out of the table
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// Cap on computed waits, so extreme RATE_LIMIT_RPS/RATE_LIMIT_BURST ratios stay representable
+const MAX_WAIT: Duration = Duration::from_secs(3600);
+
+// Tokens left for one client and when they were last topped up
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Buckets {
+    clients: HashMap<IpAddr, Bucket>,
+    swept: Instant,
+}
+
+// Token bucket per client IP: `rate` connections per second on average, with bursts
+// of up to `burst`. Shared by every accept loop.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            buckets: Mutex::new(Buckets {
+                clients: HashMap::new(),
+                swept: Instant::now(),
+            }),
+        }
+    }
+
+    // Take a token for `ip`, or return how long until one is available
+    pub fn acquire(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+
+        // Buckets that have refilled completely carry no state worth keeping
+        if now.duration_since(buckets.swept) >= SWEEP_INTERVAL {
+            let full_after = clamped_secs(self.burst / self.rate);
+            buckets
+                .clients
+                .retain(|_, bucket| now.duration_since(bucket.updated) < full_after);
+            buckets.swept = now;
+        }
+
+        let bucket = buckets.clients.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(clamped_secs((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+}
+
+// Seconds as a Duration, capped at MAX_WAIT (also for overflow and NaN)
+fn clamped_secs(secs: f64) -> Duration {
+    Duration::try_from_secs_f64(secs).map_or(MAX_WAIT, |wait| wait.min(MAX_WAIT))
+}
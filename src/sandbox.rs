@@ -0,0 +1,95 @@
+use std::{env, fs, io, path::Path, ptr};
+
+// Check whether the process is running with root privileges
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+// Resolve the GID to drop to, defaulting to the user's primary group.
+// Must run before chroot since it reads the user database.
+pub fn resolve_gid(uid: Option<u32>, gid: Option<u32>) -> Option<u32> {
+    match (gid, uid) {
+        (Some(gid), _) => Some(gid),
+        (None, Some(uid)) => {
+            let passwd = unsafe { libc::getpwuid(uid) };
+            if passwd.is_null() {
+                None
+            } else {
+                Some(unsafe { (*passwd).pw_gid })
+            }
+        }
+        (None, None) => None,
+    }
+}
+
+// Chroot into the base directory, returning false if not permitted
+pub fn enter_chroot(base_dir: &Path) -> io::Result<bool> {
+    if let Err(e) = std::os::unix::fs::chroot(base_dir) {
+        if e.kind() == io::ErrorKind::PermissionDenied {
+            eprintln!("Warning: chroot not permitted, skipping: {}", e);
+            return Ok(false);
+        }
+        return Err(e);
+    }
+    env::set_current_dir("/")?;
+
+    println!("Chrooted into {:?}", base_dir);
+    Ok(true)
+}
+
+// Drop root privileges to the configured UID/GID (setgroups, setgid, then setuid)
+pub fn drop_privileges(uid: Option<u32>, gid: Option<u32>, base_dir: &Path) -> io::Result<()> {
+    if uid.is_none() && gid.is_none() {
+        return Ok(());
+    }
+
+    if !is_root() {
+        eprintln!("Warning: not running as root, ignoring RUN_UID/RUN_GID");
+        return Ok(());
+    }
+
+    let gid = gid.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "No primary group found for RUN_UID, set RUN_GID",
+        )
+    })?;
+
+    // Group changes must happen while we are still root
+    if unsafe { libc::setgroups(0, ptr::null()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::setgid(gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if let Some(uid) = uid {
+        if unsafe { libc::setuid(uid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Make sure root cannot be regained
+        if unsafe { libc::setuid(0) } == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Privileges could not be dropped permanently",
+            ));
+        }
+    }
+
+    // Fail fast if the unprivileged user cannot serve the base directory
+    if let Err(e) = fs::read_dir(base_dir) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "Base directory not readable after dropping privileges: {}",
+                e
+            ),
+        ));
+    }
+
+    match uid {
+        Some(uid) => println!("Dropped privileges to UID {}, GID {}", uid, gid),
+        None => println!("Dropped group privileges to GID {}", gid),
+    }
+    Ok(())
+}
@@ -1,15 +1,111 @@
+use crate::log::{self, debug};
+use crate::mime::MimeTypes;
 use std::{
     env,
-    sync::{mpsc, Arc, Mutex},
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
+// Path prefixes that only scanners request on a static site
+const DEFAULT_PROBE_PATTERNS: &[&str] = &[
+    "/wp-login.php",
+    "/wp-admin",
+    "/xmlrpc.php",
+    "/phpmyadmin",
+    "/.env",
+    "/.git/",
+    "/cgi-bin/",
+];
+
+// Trailing suffixes that qualify a file rather than give its type, e.g. index.html.en
+const DEFAULT_MIME_MODIFIER_SUFFIXES: &[&str] = &[
+    "ar", "de", "en", "es", "fr", "it", "ja", "ko", "nl", "pl", "pt", "ru", "sv", "zh",
+];
+
 // Config struct to hold server configuration
 pub struct Config {
-    pub address: String,
+    pub addresses: Vec<Result<SocketAddr, String>>, // Unusable entries keep their error
+    pub bind_failure_continue: bool,
     pub base_dir: String,
     pub index_file: String,
     pub thread_count: usize,
+    pub reserve_workers: usize,
+    pub queue_capacity: usize,
+    pub reuseport_sharding: bool,
+    pub worker_stack_size: Option<usize>,
+    pub debug_errors: bool,
+    pub debug: bool,
+    pub merge_slashes: bool,
+    pub normalize_backslashes: bool,
+    pub allow_dotfiles: bool,
+    pub deny_patterns: Vec<String>,
+    pub symlink_loop_status: &'static str,
+    pub follow_symlinks: bool,
+    pub run_uid: Option<u32>,
+    pub run_gid: Option<u32>,
+    pub chroot: bool,
+    pub livez_path: String,
+    pub readyz_path: String,
+    pub status_path: String,
+    pub special_endpoints_shadowable: bool,
+    pub json_error_prefixes: Vec<String>,
+    pub error_404: Option<String>,
+    pub error_500: Option<String>,
+    pub response_jitter: Option<(Duration, Duration)>,
+    pub block_probes: bool,
+    pub probe_patterns: Vec<String>,
+    pub summary_interval: Option<Duration>,
+    pub mime_types: MimeTypes,
+    pub omit_fallback_content_type: bool,
+    pub charset: String,
+    pub dry_serve: bool,
+    pub gone_paths: Vec<String>,
+    pub gone_body: Option<String>,
+    pub bandwidth_limit: Option<u64>,
+    pub max_transfer_time: Option<Duration>,
+    pub rate_limit: Option<(f64, f64)>,
+    pub tcp_keepalive: bool,
+    pub tcp_keepalive_idle: Duration,
+    pub tcp_keepalive_interval: Duration,
+    pub tcp_keepalive_retries: u32,
+    pub drain_timeout: Option<Duration>,
+    pub drain_max_bytes: Option<u64>,
+    pub max_header_size: usize,
+    pub gzip: bool,
+    pub gzip_min_size: u64,
+    pub buffer_max_size: u64,
+    pub stream_extensions: Vec<String>,
+    pub buffer_extensions: Vec<String>,
+    pub strip_trailing_slash: bool,
+    pub directory_slash_redirect: bool,
+    pub index_redirect: bool,
+    pub autoindex: bool,
+    pub spa_mode: bool,
+    pub default_favicon: bool,
+    pub keep_alive: bool,
+    pub keep_alive_timeout: Duration,
+    pub keep_alive_max: usize,
+    pub reject_request_body: bool,
+    pub body_drain_max: u64,
+    pub trust_proxy: bool,
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub template_extensions: Vec<String>,
+    pub template_vars: Vec<(String, String)>,
+    pub generated_etag_max_size: u64,
+    pub shutdown_timeout: Duration,
+    pub log_level: log::Level,
+    pub log_format: log::Format,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub proxy_prefix: Option<String>,
+    pub proxy_upstream: Option<(String, SocketAddr)>,
+    pub log_route: Option<bool>,
 }
 
 impl Config {
@@ -21,51 +117,472 @@ impl Config {
             .max(1); // Ensure at least 1 thread
 
         Self {
-            address: env::var("ADDR").unwrap_or_else(|_| "127.0.0.1:8080".to_string()),
+            // ADDRS (or ADDR) may list several comma-separated addresses
+            addresses: env_list_or(
+                if env::var_os("ADDRS").is_some() {
+                    "ADDRS"
+                } else {
+                    "ADDR"
+                },
+                &["127.0.0.1:8080"],
+            )
+            .iter()
+            .map(|address| resolve_address(address))
+            .collect(),
+            bind_failure_continue: env::var("BIND_FAILURE").is_ok_and(|v| v == "continue"),
             base_dir: env::var("DIR").unwrap_or_else(|_| "./www".to_string()),
             index_file: env::var("INDEX").unwrap_or_else(|_| "index.html".to_string()),
             thread_count,
+            // Workers held back for health checks under load, never all of them
+            reserve_workers: env_parse("RESERVE_WORKERS")
+                .unwrap_or(0)
+                .min(thread_count - 1),
+            // Connections waiting for a worker before new ones are shed with 503
+            queue_capacity: env_parse("QUEUE_CAPACITY").unwrap_or(1024).max(1),
+            reuseport_sharding: env_flag("REUSEPORT_SHARDING", false),
+            worker_stack_size: worker_stack_size(),
+            debug_errors: env_flag("DEBUG_ERRORS", false),
+            // Debug headers such as X-Served-Path expose the filesystem layout, keep off in production
+            debug: env_flag("DEBUG", false),
+            merge_slashes: env_flag("MERGE_SLASHES", true),
+            normalize_backslashes: env_flag("NORMALIZE_BACKSLASHES", false),
+            // Hidden files (.git, .env, ...) answer 404 unless ALLOW_DOTFILES, as do paths
+            // matching DENY globs, e.g. DENY="*.bak,secrets/*"
+            allow_dotfiles: env_flag("ALLOW_DOTFILES", false),
+            deny_patterns: env_list("DENY"),
+            // Symlink loops answer 404 like missing files, or 508 with SYMLINK_LOOP_STATUS=508
+            symlink_loop_status: match env_parse("SYMLINK_LOOP_STATUS") {
+                Some(508) => "508 Loop Detected",
+                _ => "404 Not Found",
+            },
+            // Symlinks resolving inside base_dir are served; false refuses them all
+            follow_symlinks: env_flag("FOLLOW_SYMLINKS", true),
+            run_uid: env_parse("RUN_UID"),
+            run_gid: env_parse("RUN_GID"),
+            chroot: env_flag("CHROOT", false),
+            // Set to an empty string to disable the endpoint
+            livez_path: env::var("LIVEZ_PATH").unwrap_or_else(|_| "/livez".to_string()),
+            readyz_path: env::var("READYZ_PATH").unwrap_or_else(|_| "/readyz".to_string()),
+            // Worker, queue and traffic metrics as JSON, off unless given a path (e.g. "/__status")
+            status_path: env::var("STATUS_ENDPOINT").unwrap_or_default(),
+            // Built-in endpoints win over same-named files unless this is set
+            special_endpoints_shadowable: env_flag("SPECIAL_ENDPOINTS_SHADOWABLE", false),
+            json_error_prefixes: env_list("JSON_ERROR_PREFIXES"),
+            // HTML error pages relative to base_dir, defaulting to /404.html and /500.html
+            error_404: env::var("ERROR_404").ok(),
+            error_500: env::var("ERROR_500").ok(),
+            // Load-testing aid only: RESPONSE_JITTER_MS="min-max" adds a random delay
+            response_jitter: env::var("RESPONSE_JITTER_MS")
+                .ok()
+                .and_then(|value| parse_range_ms(&value)),
+            block_probes: env_flag("BLOCK_PROBES", false),
+            probe_patterns: env_list_or("PROBE_PATTERNS", DEFAULT_PROBE_PATTERNS),
+            // Periodic activity summary, off unless SUMMARY_INTERVAL (seconds) is set
+            summary_interval: env_parse("SUMMARY_INTERVAL")
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs),
+            // Extra exact-filename types, e.g. FILENAME_TYPES="Procfile=text/plain", and
+            // extension types, e.g. MIME_OVERRIDES="wasm=application/wasm,map=application/json"
+            mime_types: MimeTypes::new(
+                &env_list("FILENAME_TYPES"),
+                env_flag("FILENAME_TYPES_CASE_INSENSITIVE", false),
+                &env_list("MIME_OVERRIDES"),
+                env::var("MIME_TYPES_FILE").ok().as_deref(),
+                &env_list_or("MIME_MODIFIER_SUFFIXES", DEFAULT_MIME_MODIFIER_SUFFIXES),
+            ),
+            omit_fallback_content_type: env_flag("OMIT_FALLBACK_CONTENT_TYPE", false),
+            // Charset added to text content types, empty to leave them bare
+            charset: env::var("CHARSET").unwrap_or_else(|_| "utf-8".to_string()),
+            // Permanently removed paths answered with 410, e.g. GONE_PATHS="/old.html,/blog/2019/*"
+            gone_paths: env_list("GONE_PATHS"),
+            gone_body: env::var("GONE_BODY").ok(),
+            // Resolve and log requests but respond without bodies (routing audits). Files,
+            // listings, the built-in favicon and error pages are withheld; built-in
+            // endpoints, proxied responses, GONE_BODY and JSON or DEBUG_ERRORS error
+            // bodies are not file contents and are still sent.
+            dry_serve: env_flag("DRY_SERVE", false),
+            // Per-connection body throughput cap in bytes/sec, disabled when unset or 0
+            bandwidth_limit: env_parse("BANDWIDTH_LIMIT").filter(|&limit| limit > 0),
+            // Seconds one response may take before its body is cut off and the connection
+            // closed, bounding how long a giant download holds a worker. Files are served
+            // with Accept-Ranges, so clients can resume from where the cutoff left them.
+            max_transfer_time: env_parse("MAX_TRANSFER_TIME")
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs),
+            // New connections per second from one IP, with bursts up to RATE_LIMIT_BURST
+            // (default: one second's worth); beyond that clients get 429
+            rate_limit: env_parse::<f64>("RATE_LIMIT_RPS")
+                .filter(|&rate| rate > 0.0 && rate.is_finite())
+                .map(|rate| {
+                    let burst = env_parse::<f64>("RATE_LIMIT_BURST")
+                        .filter(|burst| burst.is_finite())
+                        .unwrap_or(rate);
+                    (rate, burst.max(1.0))
+                }),
+            // SO_KEEPALIVE probes to detect dead peers (distinct from HTTP keep-alive)
+            tcp_keepalive: env_flag("TCP_KEEPALIVE", true),
+            tcp_keepalive_idle: Duration::from_secs(env_parse("TCP_KEEPALIVE_IDLE").unwrap_or(60)),
+            tcp_keepalive_interval: Duration::from_secs(
+                env_parse("TCP_KEEPALIVE_INTERVAL").unwrap_or(10),
+            ),
+            tcp_keepalive_retries: env_parse("TCP_KEEPALIVE_COUNT").unwrap_or(5),
+            // Per-connection caps on finishing a transfer once shutdown starts, unbounded by default
+            drain_timeout: env_parse("DRAIN_TIMEOUT").map(Duration::from_secs),
+            drain_max_bytes: env_parse("DRAIN_MAX_BYTES"),
+            // Largest request line plus headers accepted before answering 431
+            max_header_size: env_parse("MAX_HEADER_SIZE").unwrap_or(8192).max(1024),
+            // Gzip text-based files for clients that accept it, skipping tiny files
+            gzip: env_flag("GZIP", true),
+            gzip_min_size: env_parse("GZIP_MIN_SIZE").unwrap_or(1024),
+            // Files up to BUFFER_MAX_SIZE are read whole before sending, larger ones are
+            // streamed; per-extension overrides, e.g. STREAM_EXTENSIONS="mp4,webm"
+            buffer_max_size: env_parse("BUFFER_MAX_SIZE").unwrap_or(64 * 1024),
+            stream_extensions: env_list("STREAM_EXTENSIONS"),
+            buffer_extensions: env_list("BUFFER_EXTENSIONS"),
+            // Canonical URLs without a trailing slash: `/about/` -> 301 `/about` when a file
+            strip_trailing_slash: env_flag("STRIP_TRAILING_SLASH", false),
+            // Directories without a trailing slash: `/docs` -> 301 `/docs/`
+            directory_slash_redirect: env_flag("DIRECTORY_SLASH_REDIRECT", true),
+            // Requests naming the index file: `/dir/index.html` -> 301 `/dir/`
+            index_redirect: env_flag("INDEX_REDIRECT", false),
+            // Generate a listing for directories without an index file
+            autoindex: env_flag("AUTOINDEX", false),
+            // Serve the root index for unknown non-asset paths that accept HTML
+            spa_mode: env_flag("SPA_MODE", false),
+            // Answer /favicon.ico with a built-in icon when base_dir has none
+            default_favicon: env_flag("DEFAULT_FAVICON", true),
+            // HTTP persistent connections: idle wait (seconds) and requests per connection
+            keep_alive: env_flag("KEEP_ALIVE", true),
+            keep_alive_timeout: Duration::from_secs(
+                env_parse("KEEP_ALIVE_TIMEOUT").unwrap_or(5).max(1),
+            ),
+            keep_alive_max: env_parse("KEEP_ALIVE_MAX").unwrap_or(100).max(1),
+            // GET/HEAD/OPTIONS bodies: 400 when strict, else drained up to this many bytes
+            reject_request_body: env_flag("REJECT_REQUEST_BODY", false),
+            body_drain_max: env_parse("BODY_DRAIN_MAX").unwrap_or(64 * 1024),
+            // Only behind a proxy that sets them: reuse X-Request-Id and similar headers
+            trust_proxy: env_flag("TRUST_PROXY", false),
+            // Socket timeouts (seconds) so stalled clients cannot hold a worker, 0 disables
+            read_timeout: Some(env_parse("READ_TIMEOUT").unwrap_or(30))
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs),
+            write_timeout: Some(env_parse("WRITE_TIMEOUT").unwrap_or(30))
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs),
+            // `{{KEY}}` substitution in files with these extensions, e.g. TEMPLATE_EXTENSIONS="html"
+            // and TEMPLATE_VARS="VERSION=1.2.0,YEAR=2026"
+            template_extensions: env_list("TEMPLATE_EXTENSIONS"),
+            template_vars: env_list("TEMPLATE_VARS")
+                .into_iter()
+                .filter_map(|entry| match entry.split_once('=') {
+                    Some((key, value)) if !key.trim().is_empty() => {
+                        Some((key.trim().to_string(), value.trim().to_string()))
+                    }
+                    _ => {
+                        eprintln!("Ignoring malformed template variable: {}", entry);
+                        None
+                    }
+                })
+                .collect(),
+            // Generated bodies up to this size are hashed for a strong ETag, 0 disables.
+            // Larger ones are sent without any ETag (not even a weak one).
+            generated_etag_max_size: env_parse("GENERATED_ETAG_MAX_SIZE").unwrap_or(1024 * 1024),
+            // How long shutdown waits (seconds) for in-flight requests before exiting anyway
+            shutdown_timeout: Duration::from_secs(env_parse("SHUTDOWN_TIMEOUT").unwrap_or(30)),
+            // LOG_LEVEL=error|info|debug and LOG_FORMAT=clf|json for the access log
+            log_level: env_parse("LOG_LEVEL").unwrap_or(log::Level::Info),
+            log_format: env_parse("LOG_FORMAT").unwrap_or(log::Format::Common),
+            // PEM certificate chain and private key; HTTPS is served when both are set
+            tls_cert: env::var("TLS_CERT").ok(),
+            tls_key: env::var("TLS_KEY").ok(),
+            // Forward GET requests under PROXY_PREFIX (e.g. "/api/") to PROXY_UPSTREAM
+            // ("host:port"), resolved now so it still works after chroot
+            proxy_prefix: env::var("PROXY_PREFIX")
+                .ok()
+                .filter(|prefix| !prefix.is_empty()),
+            proxy_upstream: env::var("PROXY_UPSTREAM").ok().and_then(|upstream| {
+                match resolve_address(&upstream) {
+                    Ok(addr) => Some((upstream, addr)),
+                    Err(e) => {
+                        eprintln!("Ignoring PROXY_UPSTREAM {}", e);
+                        None
+                    }
+                }
+            }),
+            // Add the routing rule that answered each request to the access log, on by
+            // default once any rule is configured
+            log_route: env::var_os("LOG_ROUTE").map(|_| env_flag("LOG_ROUTE", false)),
+        }
+    }
+
+    // Whether access log lines name the matched rule (LOG_ROUTE, else any rule configured)
+    pub fn routes_logged(&self) -> bool {
+        self.log_route.unwrap_or(
+            self.proxy_prefix.is_some()
+                || !self.gone_paths.is_empty()
+                || self.strip_trailing_slash
+                || self.index_redirect
+                || self.spa_mode,
+        )
+    }
+}
+
+// Read and parse an optional value from the environment
+fn env_parse<T: std::str::FromStr>(name: &str) -> Option<T> {
+    env::var(name).ok()?.trim().parse().ok()
+}
+
+// Worker stack size in bytes from WORKER_STACK_SIZE. When unset or invalid the
+// standard library default is used (2 MiB, or RUST_MIN_STACK if set).
+fn worker_stack_size() -> Option<usize> {
+    const MIN_STACK_SIZE: usize = 64 * 1024;
+
+    let value = env::var("WORKER_STACK_SIZE").ok()?;
+    match value.trim().parse::<usize>() {
+        Ok(size) if size >= MIN_STACK_SIZE => Some(size),
+        _ => {
+            eprintln!(
+                "Ignoring WORKER_STACK_SIZE={}: expected a byte count of at least {}",
+                value, MIN_STACK_SIZE
+            );
+            None
         }
     }
 }
 
+// Parse a "min-max" (or just "max") millisecond range
+fn parse_range_ms(value: &str) -> Option<(Duration, Duration)> {
+    let (min, max) = match value.split_once('-') {
+        Some((min, max)) => (min.trim().parse().ok()?, max.trim().parse().ok()?),
+        None => (0, value.trim().parse().ok()?),
+    };
+    if min > max || max == 0 {
+        return None;
+    }
+    Some((Duration::from_millis(min), Duration::from_millis(max)))
+}
+
+// Read a comma-separated list from the environment
+fn env_list(name: &str) -> Vec<String> {
+    env::var(name)
+        .map(|value| {
+            value
+                .split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Resolve a listen address such as "0.0.0.0:80", "[::]:80" or "localhost:8080"
+// to the first socket address it names, or describe why it cannot be used
+fn resolve_address(address: &str) -> Result<SocketAddr, String> {
+    match address.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(addr)) => Ok(addr),
+        Ok(None) => Err(format!("{}: did not resolve", address)),
+        Err(e) => Err(format!("{}: {}", address, e)),
+    }
+}
+
+// Read a comma-separated list from the environment, with a default when unset
+fn env_list_or(name: &str, default: &[&str]) -> Vec<String> {
+    if env::var_os(name).is_some() {
+        env_list(name)
+    } else {
+        default.iter().map(|item| item.to_string()).collect()
+    }
+}
+
+// Read a boolean flag from the environment ("1", "true", "yes" or "on")
+fn env_flag(name: &str, default: bool) -> bool {
+    match env::var(name) {
+        Ok(value) => matches!(
+            value.trim().to_ascii_lowercase().as_str(),
+            "1" | "true" | "yes" | "on"
+        ),
+        Err(_) => default,
+    }
+}
+
+// Server-wide counters shared by the accept loop and workers
+#[derive(Default)]
+pub struct Stats {
+    pub connections_accepted: AtomicU64,
+    pub requests_served: AtomicU64,
+    pub bytes_sent: AtomicU64,
+    pub errors: AtomicU64,
+    pub aborted_transfers: AtomicU64,
+    pub aborted_bytes: AtomicU64,
+    pub truncated_transfers: AtomicU64, // Bodies cut off at MAX_TRANSFER_TIME
+    pub blocked_probes: AtomicU64,      // Scanner probes dropped by BLOCK_PROBES
+    pub busy_workers: AtomicUsize,      // Connections currently being served
+    pub queued_jobs: Arc<AtomicUsize>,  // Connections waiting for a pool worker
+    pub dispatch_failures: AtomicU64,   // Connections the pool could not accept
+    pub bytes_by_type: [AtomicU64; CONTENT_TYPE_BUCKETS.len()],
+}
+
+// Top-level content types tracked for bytes served; anything else counts as "other"
+pub const CONTENT_TYPE_BUCKETS: [&str; 7] = [
+    "text",
+    "image",
+    "video",
+    "audio",
+    "font",
+    "application",
+    "other",
+];
+
+impl Stats {
+    // Record a completed response (4xx and 5xx count as errors)
+    pub fn record_response(&self, status: u16, bytes_sent: u64, content_type: Option<&str>) {
+        self.requests_served.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes_sent, Ordering::Relaxed);
+        if status >= 400 {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // Bucket by top-level type only, keeping the number of counters bounded
+        let top_level = content_type.and_then(|content_type| content_type.split('/').next());
+        let bucket = CONTENT_TYPE_BUCKETS
+            .iter()
+            .position(|&bucket| Some(bucket) == top_level)
+            .unwrap_or(CONTENT_TYPE_BUCKETS.len() - 1);
+        self.bytes_by_type[bucket].fetch_add(bytes_sent, Ordering::Relaxed);
+    }
+
+    // Record a response the client disconnected from partway through
+    pub fn record_abort(&self, bytes_sent: u64) {
+        self.aborted_transfers.fetch_add(1, Ordering::Relaxed);
+        self.aborted_bytes.fetch_add(bytes_sent, Ordering::Relaxed);
+    }
+
+    // Take a snapshot of the bytes served per content type bucket
+    pub fn bytes_by_type(&self) -> [u64; CONTENT_TYPE_BUCKETS.len()] {
+        std::array::from_fn(|i| self.bytes_by_type[i].load(Ordering::Relaxed))
+    }
+
+    // Take a snapshot of all counters
+    pub fn snapshot(&self) -> [u64; 8] {
+        [
+            self.connections_accepted.load(Ordering::Relaxed),
+            self.requests_served.load(Ordering::Relaxed),
+            self.bytes_sent.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed),
+            self.aborted_transfers.load(Ordering::Relaxed),
+            self.aborted_bytes.load(Ordering::Relaxed),
+            self.truncated_transfers.load(Ordering::Relaxed),
+            self.blocked_probes.load(Ordering::Relaxed),
+        ]
+    }
+}
+
 // Define Job type
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
 // ThreadPool struct to hold workers and sender
 pub struct ThreadPool {
     workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>, // Option to allow proper Drop handling
+    sender: Option<mpsc::SyncSender<Job>>, // Option to allow proper Drop handling
+    queued: Arc<AtomicUsize>,              // Jobs sent but not yet picked up by a worker
+    capacity: usize,
+}
+
+// Why a job was not queued
+pub enum ExecuteError {
+    Full,   // The queue is at capacity
+    Closed, // The pool is shutting down or its workers are gone
 }
 
 // Implement ThreadPool methods
 impl ThreadPool {
-    pub fn new(size: usize) -> Self {
+    // `queued` tracks the queue depth, shared so it can be reported elsewhere
+    pub fn new(
+        size: usize,
+        capacity: usize,
+        queued: Arc<AtomicUsize>,
+        stack_size: Option<usize>,
+    ) -> Self {
         assert!(size > 0, "Thread pool size must be greater than 0");
-        let (sender, receiver) = mpsc::channel();
+        assert!(
+            capacity > 0,
+            "Thread pool queue capacity must be greater than 0"
+        );
+        let (sender, receiver) = mpsc::sync_channel(capacity);
         let receiver = Arc::new(Mutex::new(receiver));
 
         // Create workers
         let workers = (0..size)
-            .map(|id| Worker::new(id, Arc::clone(&receiver)))
+            .map(|id| {
+                let receiver = Arc::clone(&receiver);
+                Worker::new(id, receiver, Arc::clone(&queued), stack_size)
+            })
             .collect();
 
         Self {
             workers,
             sender: Some(sender),
+            queued,
+            capacity,
         }
     }
 
-    // Execute a closure on the thread pool
-    pub fn execute<F>(&self, job: F)
+    // Whether `execute` would find the queue full. Exact for a single producer such as
+    // the accept loop, since workers only ever shrink the queue.
+    pub fn would_block(&self) -> bool {
+        self.queued.load(Ordering::Relaxed) >= self.capacity
+    }
+
+    // Queue a closure on the thread pool without blocking; a full queue is reported
+    // so the caller can shed the work instead
+    pub fn execute<F>(&self, job: F) -> Result<(), ExecuteError>
     where
         F: FnOnce() + Send + 'static,
     {
-        if let Some(sender) = &self.sender {
-            if sender.send(Box::new(job)).is_err() {
-                eprintln!("Failed to send job: receiver may be closed");
+        let Some(sender) = &self.sender else {
+            return Err(ExecuteError::Closed);
+        };
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        let result = sender.try_send(Box::new(job));
+        if result.is_err() {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+        }
+        result.map_err(|e| match e {
+            mpsc::TrySendError::Full(_) => ExecuteError::Full,
+            mpsc::TrySendError::Disconnected(_) => ExecuteError::Closed,
+        })
+    }
+
+    // Stop taking jobs and wait up to `timeout` for queued and running jobs to finish.
+    // Returns the number of workers still busy at the deadline; those are detached.
+    pub fn shutdown_timeout(&mut self, timeout: Duration) -> usize {
+        self.sender.take(); // Workers exit once the queue is empty
+        let threads = self
+            .workers
+            .iter_mut()
+            .filter_map(|worker| worker.thread.take())
+            .collect();
+        join_with_deadline(threads, timeout)
+    }
+}
+
+// Join threads as they finish, giving up after `timeout`. Returns how many were
+// still running at the deadline.
+pub fn join_with_deadline(mut threads: Vec<thread::JoinHandle<()>>, timeout: Duration) -> usize {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let (finished, running): (Vec<_>, Vec<_>) =
+            threads.into_iter().partition(|thread| thread.is_finished());
+        for thread in finished {
+            if let Err(e) = thread.join() {
+                eprintln!("Failed to join thread: {:?}", e);
             }
         }
+        threads = running;
+        if threads.is_empty() || Instant::now() >= deadline {
+            return threads.len();
+        }
+        thread::sleep(Duration::from_millis(50));
     }
 }
 
@@ -91,18 +608,27 @@ pub struct Worker {
 
 // Implement Worker methods
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Self {
-        let thread = thread::Builder::new()
-            .name(format!("worker-{}", id))
+    fn new(
+        id: usize,
+        receiver: Arc<Mutex<mpsc::Receiver<Job>>>,
+        queued: Arc<AtomicUsize>,
+        stack_size: Option<usize>,
+    ) -> Self {
+        let mut builder = thread::Builder::new().name(format!("worker-{}", id));
+        if let Some(stack_size) = stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+        let thread = builder
             .spawn(move || loop {
                 let job = receiver.lock().unwrap().recv();
                 match job {
                     Ok(task) => {
-                        println!("Worker {} executing a job", id);
+                        queued.fetch_sub(1, Ordering::Relaxed);
+                        debug!("Worker {} executing a job", id);
                         task();
                     }
                     Err(_) => {
-                        println!("Worker {} shutting down", id);
+                        debug!("Worker {} shutting down", id);
                         break;
                     }
                 }
@@ -0,0 +1,24 @@
+// Replace `{{KEY}}` placeholders with configured values. Unknown keys and unclosed
+// braces are left as they are so ordinary page content passes through untouched.
+pub fn render(template: &str, vars: &[(String, String)]) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            break;
+        };
+        rendered.push_str(&rest[..start]);
+
+        let key = after[..end].trim();
+        match vars.iter().find(|(name, _)| name == key) {
+            Some((_, value)) => rendered.push_str(value),
+            None => rendered.push_str(&rest[start..start + 2 + end + 2]),
+        }
+        rest = &after[end + 2..];
+    }
+
+    rendered.push_str(rest);
+    rendered
+}
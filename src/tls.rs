@@ -0,0 +1,62 @@
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use std::{io, net::TcpStream, sync::Arc};
+
+// Load a PEM certificate chain and private key into a rustls server config.
+// Fails if either file cannot be parsed or the key does not match the certificate.
+pub fn load_config(cert_path: &str, key_path: &str) -> io::Result<Arc<ServerConfig>> {
+    let invalid = |what: &str, path: &str, e: &dyn std::fmt::Display| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Failed to load TLS {} from {}: {}", what, path, e),
+        )
+    };
+
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid("certificate", cert_path, &e))?;
+    if certs.is_empty() {
+        return Err(invalid("certificate", cert_path, &"no certificates found"));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| invalid("key", key_path, &e))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid TLS certificate or key: {}", e),
+            )
+        })?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+// A client connection, plain TCP or TLS over TCP
+pub trait Stream: io::Read + io::Write + Send {
+    // The underlying socket, for addresses, timeouts and socket options
+    fn socket(&self) -> &TcpStream;
+
+    // Close the session cleanly before the socket is dropped
+    fn close(&mut self) {}
+}
+
+impl Stream for TcpStream {
+    fn socket(&self) -> &TcpStream {
+        self
+    }
+}
+
+impl Stream for StreamOwned<ServerConnection, TcpStream> {
+    fn socket(&self) -> &TcpStream {
+        &self.sock
+    }
+
+    // Send close_notify so clients can tell a complete response from a truncated one
+    fn close(&mut self) {
+        self.conn.send_close_notify();
+        let _ = self.conn.complete_io(&mut self.sock);
+    }
+}